@@ -0,0 +1,40 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "ffi")]
+    generate_header();
+}
+
+/// Generate the C header in `OUT_DIR`, the committed `include/neerg.h` is only compared with it
+#[cfg(feature = "ffi")]
+fn generate_header() {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=include/neerg.h");
+
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+
+    // A broken header shouldn't prevent building the Rust crate
+    let bindings = match cbindgen::generate_with_config(&crate_dir, config) {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            println!("cargo:warning=unable to generate the C header: {}", e);
+            return;
+        }
+    };
+
+    let header = out_dir.join("neerg.h");
+    bindings.write_to_file(&header);
+    if fs::read(&header).ok() != fs::read(crate_dir.join("include/neerg.h")).ok() {
+        println!(
+            "cargo:warning=include/neerg.h is out of date, update it from {}",
+            header.display()
+        );
+    }
+}
@@ -0,0 +1,87 @@
+#ifndef NEERG_H
+#define NEERG_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit manually */
+
+#include <stddef.h>
+
+#define GA_OK 0
+
+#define GA_ERROR -1
+
+typedef struct GA_session GA_session;
+
+/**
+ * Called to choose the 2FA method and to get the code
+ *
+ * `request` is either `{"action": "get_method", "methods": [...]}`, answered with the JSON
+ * string of one of the methods, or `{"action": "get_code", "method": ...}`, answered with the
+ * JSON string of the code. The answer is written NUL-terminated in `response`, which can hold
+ * `response_len` bytes. Returns `GA_OK` on success.
+ */
+typedef int (*GA_twofactor_callback)(void *context,
+                                     const char *request,
+                                     char *response,
+                                     size_t response_len);
+
+/**
+ * Create a session from the JSON `config`, which can be `NULL` for the defaults
+ *
+ * Recognized keys: `endpoint`, `proxy`, `electrum_url`, `database_path`, `device_id_file` and
+ * `timeout`.
+ */
+int GA_create_session(const char *config, GA_session **session);
+
+int GA_destroy_session(GA_session *session);
+
+/**
+ * Register the callback used when the server asks for a 2FA code, see
+ * [`GA_twofactor_callback`]
+ */
+int GA_set_twofactor_callback(GA_session *session,
+                              GA_twofactor_callback callback,
+                              void *context);
+
+/**
+ * Login with `{"mnemonic": ..., "password": ...}`, opening and syncing every subaccount
+ */
+int GA_login(GA_session *session, const char *details);
+
+/**
+ * List the subaccounts as `[{"pointer": ..., "name": ..., "hidden": ..., "balance": ...}]`,
+ * including the archived ones
+ */
+int GA_get_subaccounts(GA_session *session, char **output);
+
+/**
+ * New address of `{"subaccount": ...}`, returned as `{"address": ...}`
+ */
+int GA_get_receive_address(GA_session *session, const char *details, char **output);
+
+/**
+ * Create an unsigned transaction from `{"subaccount": ..., "addressees": [{"address": ...,
+ * "satoshi": ...}], "send_all": ..., "fee_rate": ..., "fee_target": ...}`
+ *
+ * Returns `{"psbt": ..., "fee": ..., "sent": ..., "received": ...}` with the PSBT in base64.
+ */
+int GA_create_transaction(GA_session *session, const char *details, char **output);
+
+/**
+ * Sign `{"subaccount": ..., "psbt": ...}` with the user key and the service, which can call the
+ * 2FA callback. Returns `{"psbt": ..., "finalized": ...}`.
+ */
+int GA_sign_transaction(GA_session *session, const char *details, char **output);
+
+/**
+ * Broadcast the finalized `{"subaccount": ..., "psbt": ...}`, returning `{"txid": ...}`
+ */
+int GA_send_transaction(GA_session *session, const char *details, char **output);
+
+/**
+ * Details of the last error of the calling thread as `{"details": ...}`
+ */
+int GA_get_thread_error_details(char **output);
+
+void GA_destroy_string(char *string);
+
+#endif /* NEERG_H */
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use bdk::bitcoin;
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::secp256k1::{Message, Secp256k1, Signature};
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPubKey, Fingerprint};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::SigHashType;
+
+use crate::descriptor::GreenSubaccountDescriptor;
+use crate::signer::{parse_compact_signature, UserSigner};
+
+pub fn psbt_to_base64(psbt: &PartiallySignedTransaction) -> String {
+    base64::encode(&serialize(psbt))
+}
+
+pub fn psbt_from_base64(data: &str) -> Result<PartiallySignedTransaction, Box<dyn Error>> {
+    Ok(deserialize(&base64::decode(data.trim())?)?)
+}
+
+/// Write the PSBT to `writer`, either in binary form, usually for files, or as a base64 line
+pub fn export_psbt<W: Write>(
+    psbt: &PartiallySignedTransaction,
+    mut writer: W,
+    binary: bool,
+) -> Result<(), Box<dyn Error>> {
+    match binary {
+        true => writer.write_all(&serialize(psbt))?,
+        false => writeln!(writer, "{}", psbt_to_base64(psbt))?,
+    }
+
+    Ok(writer.flush()?)
+}
+
+/// Read a PSBT from a file (either binary or base64) or directly from a base64 string
+pub fn import_psbt(data: &str) -> Result<PartiallySignedTransaction, Box<dyn Error>> {
+    let path = Path::new(data);
+    if !path.is_file() {
+        return psbt_from_base64(data);
+    }
+
+    let content = fs::read(path)?;
+    match deserialize(&content) {
+        Ok(psbt) => Ok(psbt),
+        Err(_) => psbt_from_base64(&String::from_utf8(content)?),
+    }
+}
+
+/// Check that every input of `psbt` has a valid signature from the key with `fingerprint`
+///
+/// Rejects PSBTs of other wallets, which have none of our keys, and signatures made for a
+/// different transaction.
+pub fn check_signed_psbt(
+    psbt: &PartiallySignedTransaction,
+    fingerprint: Fingerprint,
+) -> Result<(), Box<dyn Error>> {
+    let secp = Secp256k1::verification_only();
+    let mut cache = SigHashCache::new(&psbt.global.unsigned_tx);
+
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        let key = input
+            .hd_keypaths
+            .iter()
+            .find(|(_, (f, _))| *f == fingerprint)
+            .map(|(key, _)| key)
+            .ok_or_else(|| format!("input {} doesn't belong to the wallet", index))?;
+        let signature = input
+            .partial_sigs
+            .get(key)
+            .ok_or_else(|| format!("input {} is not signed", index))?;
+        let (script, utxo) = match (&input.witness_script, &input.witness_utxo) {
+            (Some(script), Some(utxo)) => (script, utxo),
+            _ => return Err(format!("input {} has no witness script or utxo", index).into()),
+        };
+
+        let (sighash_type, signature) = signature
+            .split_last()
+            .ok_or_else(|| format!("empty signature for input {}", index))?;
+        let sighash = cache.signature_hash(
+            index,
+            script,
+            utxo.value,
+            SigHashType::from_u32(*sighash_type as u32),
+        );
+        secp.verify(
+            &Message::from_slice(&sighash[..])?,
+            &Signature::from_der(signature)?,
+            &key.key,
+        )
+        .map_err(|_| format!("invalid signature for input {}", index))?;
+    }
+
+    Ok(())
+}
+
+/// Export the xpubs an [`AirGappedSigner`] needs to login and build the first `subaccounts`
+/// subaccounts, as a JSON object mapping each derivation path to its xpub
+pub fn export_xpubs(signer: &dyn UserSigner, subaccounts: u16) -> Result<String, Box<dyn Error>> {
+    let mut xpubs = BTreeMap::new();
+    for pointer in 0..subaccounts {
+        let pointer = match pointer {
+            0 => None,
+            p => Some(p),
+        };
+        let path = GreenSubaccountDescriptor::get_user_path(pointer);
+
+        xpubs.insert(path.to_string(), signer.get_xpub(&path)?.to_string());
+    }
+
+    Ok(serde_json::to_string_pretty(&xpubs)?)
+}
+
+/// Watch-only user signer for an online machine, paired with an offline one that holds the keys
+///
+/// Xpubs are read from the output of [`export_xpubs`], the login challenge is written to
+/// `output` to be copied to the offline machine, which signs it, and the signature is read back
+/// from `input`. PSBTs are expected to already carry the user signatures.
+pub struct AirGappedSigner {
+    xpubs: BTreeMap<String, ExtendedPubKey>,
+    io: Mutex<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)>,
+}
+
+impl fmt::Debug for AirGappedSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AirGappedSigner")
+            .field("xpubs", &self.xpubs)
+            .finish()
+    }
+}
+
+impl AirGappedSigner {
+    pub fn from_file<P, R, W>(path: P, input: R, output: W) -> Result<Self, Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+        R: BufRead + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let xpubs: BTreeMap<String, String> = serde_json::from_slice(&fs::read(path)?)?;
+        let xpubs = xpubs
+            .into_iter()
+            .map(|(path, xpub)| Ok((path, ExtendedPubKey::from_str(&xpub)?)))
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        Ok(AirGappedSigner {
+            xpubs,
+            io: Mutex::new((Box::new(input), Box::new(output))),
+        })
+    }
+}
+
+impl UserSigner for AirGappedSigner {
+    fn get_xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, Box<dyn Error>> {
+        self.xpubs
+            .get(&path.to_string())
+            .cloned()
+            .ok_or_else(|| format!("missing xpub for `{}`", path).into())
+    }
+
+    fn sign_message(&self, path: &DerivationPath, msg: &str) -> Result<Signature, Box<dyn Error>> {
+        let mut io = self.io.lock().unwrap();
+        let (input, output) = &mut *io;
+
+        writeln!(
+            output,
+            "Sign this message on the offline machine with the key at `{}`:",
+            path
+        )?;
+        writeln!(output, "{}", msg)?;
+        write!(output, "Paste the base64 signature: ")?;
+        output.flush()?;
+
+        let mut signature = String::new();
+        if input.read_line(&mut signature)? == 0 {
+            return Err("no signature for the message".into());
+        }
+
+        parse_compact_signature(&signature)
+    }
+
+    fn sign_psbt(&self, _psbt: &mut PartiallySignedTransaction) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
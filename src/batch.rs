@@ -0,0 +1,277 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use url::Url;
+
+use bdk::bitcoin;
+
+use bitcoin::util::amount::{Amount, Denomination};
+use bitcoin::{Address, Network, Script};
+
+/// Fee rate used by Bitcoin Core to define dust, in sat/vB
+const DUST_RELAY_FEE: u64 = 3;
+
+/// Value below which an output with `script_pubkey` is dust and rejected by the network, 546 sat
+/// for P2PKH and 294 sat for P2WPKH
+///
+/// Like Bitcoin Core, an output is dust when spending it costs more than a third of its value.
+pub fn get_dust_limit(script_pubkey: &Script) -> u64 {
+    if script_pubkey.is_provably_unspendable() {
+        return 0;
+    }
+
+    // Value, script length and script
+    let len = script_pubkey.len() as u64;
+    let output_size = 8 + if len < 0xfd { 1 } else { 3 } + len;
+    // Outpoint, script_sig length, sequence and the signature, discounted for segwit
+    let input_size = match script_pubkey.is_witness_program() {
+        true => 32 + 4 + 1 + 107 / 4 + 4,
+        false => 32 + 4 + 1 + 107 + 4,
+    };
+
+    (output_size + input_size) * DUST_RELAY_FEE
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    pub address: Address,
+    /// Amount in satoshi
+    pub amount: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonRecipient {
+    Uri(String),
+    Pair { address: String, amount: u64 },
+}
+
+/// Parse the recipients of a batch payment
+///
+/// The content can be either a JSON array, whose elements are `{"address": ..., "amount": ...}`
+/// objects or BIP21 URIs, or a CSV with an `address,amount` pair or a BIP21 URI on every line.
+/// Amounts are in satoshi, except in the URIs where they are in BTC as mandated by BIP21.
+pub fn parse_recipients(content: &str, network: Network) -> Result<Vec<Recipient>, Box<dyn Error>> {
+    let recipients = match content.trim_start().starts_with('[') {
+        true => parse_json(content)?,
+        false => parse_csv(content)?,
+    };
+
+    validate_recipients(&recipients, network)?;
+    Ok(recipients)
+}
+
+fn parse_json(content: &str) -> Result<Vec<Recipient>, Box<dyn Error>> {
+    serde_json::from_str::<Vec<JsonRecipient>>(content)?
+        .into_iter()
+        .map(|recipient| match recipient {
+            JsonRecipient::Uri(uri) => parse_bip21(&uri),
+            JsonRecipient::Pair { address, amount } => Ok(Recipient {
+                address: Address::from_str(&address)?,
+                amount,
+            }),
+        })
+        .collect()
+}
+
+fn parse_csv(content: &str) -> Result<Vec<Recipient>, Box<dyn Error>> {
+    let mut recipients = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // A URI takes the whole line, its label or message may contain commas
+        if is_bip21(line) {
+            recipients.push(parse_bip21(line).map_err(|e| format!("line {}: {}", i + 1, e))?);
+            continue;
+        }
+
+        let cells = line.splitn(2, ',').map(str::trim).collect::<Vec<_>>();
+        let recipient = match cells.as_slice() {
+            // Optional header, before any recipient
+            ["address", "amount"] if recipients.is_empty() => continue,
+            [address, amount] => (|| -> Result<_, Box<dyn Error>> {
+                Ok(Recipient {
+                    address: Address::from_str(address)?,
+                    amount: amount.parse()?,
+                })
+            })(),
+            _ => Err("expected an `address,amount` pair or a BIP21 URI".into()),
+        };
+        recipients.push(recipient.map_err(|e| format!("line {}: {}", i + 1, e))?);
+    }
+
+    Ok(recipients)
+}
+
+/// The scheme is case insensitive, URIs in QR codes are often uppercase
+fn is_bip21(uri: &str) -> bool {
+    uri.get(..8)
+        .map_or(false, |scheme| scheme.eq_ignore_ascii_case("bitcoin:"))
+}
+
+/// Parse a `bitcoin:<address>?amount=<btc>` URI, the amount is mandatory
+pub fn parse_bip21(uri: &str) -> Result<Recipient, Box<dyn Error>> {
+    if !is_bip21(uri) {
+        return Err(format!("`{}` is not a bitcoin URI", uri).into());
+    }
+    let url = Url::parse(uri)?;
+
+    let address = Address::from_str(url.path())?;
+    let mut amount = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "amount" => amount = Some(Amount::from_str_in(&value, Denomination::Bitcoin)?),
+            // Unknown parameters starting with `req-` must be understood, the others can be ignored
+            key if key.starts_with("req-") => {
+                return Err(format!("unsupported required parameter `{}`", key).into())
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Recipient {
+        address,
+        amount: amount.ok_or("missing amount in the URI")?.as_sat(),
+    })
+}
+
+fn validate_recipients(recipients: &[Recipient], network: Network) -> Result<(), Box<dyn Error>> {
+    if recipients.is_empty() {
+        return Err("no recipients".into());
+    }
+
+    for recipient in recipients {
+        // Testnet and regtest share the same legacy address prefixes
+        let same_network = match (recipient.address.network, network) {
+            (Network::Testnet, Network::Regtest) | (Network::Regtest, Network::Testnet) => true,
+            (a, b) => a == b,
+        };
+        if !same_network {
+            return Err(format!("{} is not a {} address", recipient.address, network).into());
+        }
+        let dust_limit = get_dust_limit(&recipient.address.script_pubkey());
+        if recipient.amount < dust_limit {
+            return Err(format!(
+                "the amount for {} is below the dust limit of {} sat",
+                recipient.address, dust_limit
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P2PKH: &str = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn";
+    const P2WPKH: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    fn get_recipient(address: &str, amount: u64) -> Recipient {
+        Recipient {
+            address: Address::from_str(address).unwrap(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = format!(
+            "# payroll\naddress, amount\n{}, 10000\n\n{},20000\nbitcoin:{}?amount=0.0003&label=Rent%2C%20March\n",
+            P2PKH, P2WPKH, P2PKH
+        );
+
+        assert_eq!(
+            parse_recipients(&csv, Network::Testnet).unwrap(),
+            vec![
+                get_recipient(P2PKH, 10_000),
+                get_recipient(P2WPKH, 20_000),
+                get_recipient(P2PKH, 30_000),
+            ]
+        );
+
+        // The header is only accepted before the recipients
+        let csv = format!("{},10000\naddress,amount\n", P2PKH);
+        let err = parse_recipients(&csv, Network::Testnet).unwrap_err();
+        assert!(err.to_string().starts_with("line 2: "));
+
+        let err = parse_recipients(&format!("{}\n", P2PKH), Network::Testnet).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: expected an `address,amount` pair or a BIP21 URI"
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let json = format!(
+            r#"[{{"address": "{}", "amount": 10000}}, "bitcoin:{}?amount=0.0002"]"#,
+            P2PKH, P2WPKH
+        );
+
+        assert_eq!(
+            parse_recipients(&json, Network::Testnet).unwrap(),
+            vec![get_recipient(P2PKH, 10_000), get_recipient(P2WPKH, 20_000)]
+        );
+        assert!(parse_recipients("[]", Network::Testnet).is_err());
+    }
+
+    #[test]
+    fn test_parse_bip21() {
+        assert_eq!(
+            parse_bip21(&format!("BITCOIN:{}?amount=1.5&message=thanks", P2PKH)).unwrap(),
+            get_recipient(P2PKH, 150_000_000)
+        );
+
+        // Unknown `req-` parameters must make the URI invalid
+        let err = parse_bip21(&format!("bitcoin:{}?amount=1&req-expires=1", P2PKH)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported required parameter `req-expires`"
+        );
+
+        let err = parse_bip21(&format!("bitcoin:{}?label=rent", P2PKH)).unwrap_err();
+        assert_eq!(err.to_string(), "missing amount in the URI");
+        assert!(parse_bip21(&format!("bitcoin:{}?amount=abc", P2PKH)).is_err());
+        assert!(parse_bip21(P2PKH).is_err());
+    }
+
+    #[test]
+    fn test_dust_limit() {
+        let p2pkh = Address::from_str(P2PKH).unwrap().script_pubkey();
+        let p2wpkh = Address::from_str(P2WPKH).unwrap().script_pubkey();
+        assert_eq!(get_dust_limit(&p2pkh), 546);
+        assert_eq!(get_dust_limit(&p2wpkh), 294);
+
+        let csv = format!("{},545\n", P2PKH);
+        let err = parse_recipients(&csv, Network::Testnet).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the amount for {} is below the dust limit of 546 sat",
+                P2PKH
+            )
+        );
+        assert!(parse_recipients(&format!("{},294\n", P2WPKH), Network::Testnet).is_ok());
+    }
+
+    #[test]
+    fn test_wrong_network() {
+        let csv = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2,10000\n";
+        let err = parse_recipients(csv, Network::Testnet).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2 is not a testnet address"
+        );
+
+        // Regtest shares the legacy prefixes of testnet
+        assert!(parse_recipients(&format!("{},10000\n", P2PKH), Network::Regtest).is_ok());
+    }
+}
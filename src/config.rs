@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use rand::Rng;
+
+use bdk::bitcoin;
+
+use bitcoin::hashes::hex::ToHex;
+
+use crate::rpc::RpcConfig;
+
+#[derive(Debug, Clone)]
+pub struct GAClientConfig {
+    pub(crate) endpoint: String,
+    pub(crate) realm: String,
+    pub(crate) device_id: String,
+    pub(crate) user_agent: String,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) call_timeout: Duration,
+    pub(crate) reconnect_backoff: Duration,
+    pub(crate) max_reconnect_backoff: Duration,
+    pub(crate) max_reconnect_attempts: u32,
+    pub(crate) proxy: Option<String>,
+}
+
+impl Default for GAClientConfig {
+    fn default() -> Self {
+        GAClientConfig {
+            endpoint: "wss://testwss.greenaddress.it/v2/ws/".into(),
+            realm: "realm1".into(),
+            device_id: generate_device_id(),
+            user_agent: "[v2,sw]neerg".into(),
+            connect_timeout: Duration::from_secs(30),
+            call_timeout: Duration::from_secs(60),
+            reconnect_backoff: Duration::from_secs(1),
+            max_reconnect_backoff: Duration::from_secs(60),
+            max_reconnect_attempts: 10,
+            proxy: None,
+        }
+    }
+}
+
+impl GAClientConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    pub fn realm<S: Into<String>>(mut self, realm: S) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    pub fn device_id<S: Into<String>>(mut self, device_id: S) -> Self {
+        self.device_id = device_id.into();
+        self
+    }
+
+    /// Load the device id from `path`, or generate a new one and store it there if missing
+    pub fn persisted_device_id<P: AsRef<Path>>(mut self, path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+
+        self.device_id = match fs::read_to_string(path) {
+            Ok(device_id) if !device_id.trim().is_empty() => device_id.trim().to_string(),
+            _ => {
+                let device_id = generate_device_id();
+                fs::write(path, &device_id)?;
+
+                device_id
+            }
+        };
+
+        Ok(self)
+    }
+
+    pub fn user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = timeout;
+        self
+    }
+
+    /// Wait `initial` after the first failed reconnection attempt, doubling it every time up to
+    /// `max`
+    pub fn reconnect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.reconnect_backoff = initial;
+        self.max_reconnect_backoff = max;
+        self
+    }
+
+    pub fn max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = attempts;
+        self
+    }
+
+    /// Connect through the SOCKS5 proxy at `proxy` (`host:port`), like a local Tor daemon
+    pub fn proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+}
+
+fn generate_device_id() -> String {
+    rand::thread_rng().gen::<[u8; 32]>().to_hex()
+}
+
+/// Backend used by the subaccounts to sync and broadcast
+#[derive(Debug, Clone)]
+pub enum BlockchainConfig {
+    Electrum {
+        url: String,
+        socks5: Option<String>,
+    },
+    /// Esplora REST API, like the one exposed by `electrs` on top of a local `bitcoind`
+    Esplora {
+        base_url: String,
+    },
+    /// Watch-only wallet of a bitcoind node
+    Rpc(RpcConfig),
+    /// Never touches the network, useful to only build and sign transactions
+    Offline,
+}
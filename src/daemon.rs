@@ -0,0 +1,472 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Take};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio::time::timeout;
+
+use bdk::bitcoin;
+
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::{Address, Network, OutPoint};
+
+use bdk::blockchain::{noop_progress, Blockchain};
+use bdk::database::BatchDatabase;
+use bdk::TxBuilder;
+
+use crate::batch::get_dust_limit;
+use crate::fees::FeeTarget;
+use crate::subaccount::Subaccount;
+use crate::twofactor::ParamResolver;
+use crate::types::{TwoFactorAction, TwoFactorMethod, UtxoStatus};
+use crate::wallet::GreenWallet;
+
+/// Requests bigger than this are rejected, they are all small JSON objects
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+/// Time a client has to send its request, and then to read the response
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// User of the basic authentication, like bitcoind the password is the token in the cookie file
+const COOKIE_USER: &str = "__cookie__";
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SubaccountParams {
+    #[serde(default)]
+    subaccount: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct TwoFactorParams {
+    method: TwoFactorMethod,
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendParams {
+    #[serde(default)]
+    subaccount: u16,
+    address: String,
+    /// Sends the whole balance when omitted
+    amount: Option<u64>,
+    /// sat/vbyte, overrides `fee_target`
+    fee_rate: Option<f32>,
+    fee_target: Option<String>,
+    twofactor: Option<TwoFactorParams>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestCodeParams {
+    method: TwoFactorMethod,
+}
+
+#[derive(Debug, Serialize)]
+struct UnspentOutput {
+    outpoint: OutPoint,
+    value: u64,
+    status: UtxoStatus,
+}
+
+/// JSON-RPC server over HTTP, keeping the session and the subaccounts of `wallet` open
+///
+/// Like bitcoind, every request must authenticate with the random token written to the cookie
+/// file, as the password of the `__cookie__` user. Requests from browsers, that have an `Origin`
+/// header or are not `application/json`, are rejected.
+///
+/// The 2FA code of `send` is passed in its `twofactor` parameter, SMS, phone and email codes
+/// have to be requested first with `requesttwofactorcode`. The subaccounts of `wallet` must use
+/// `resolver` for their service signer.
+///
+/// Every connection is read and answered by its own task with a timeout, so a slow client can't
+/// stall the others, while the requests are served one at a time, so the wallets are never used
+/// concurrently.
+pub struct Daemon<'a, B: Blockchain, D: BatchDatabase> {
+    wallet: &'a GreenWallet<B, D>,
+    resolver: Arc<ParamResolver>,
+    network: Network,
+    cookie_file: PathBuf,
+    token: String,
+}
+
+impl<'a, B: Blockchain, D: BatchDatabase> Daemon<'a, B, D> {
+    /// The cookie file is written by [`Daemon::serve`] with a new token on every start
+    pub fn new<P: AsRef<Path>>(
+        wallet: &'a GreenWallet<B, D>,
+        resolver: Arc<ParamResolver>,
+        cookie_file: P,
+    ) -> Self {
+        Daemon {
+            wallet,
+            resolver,
+            network: Network::Testnet,
+            cookie_file: cookie_file.as_ref().to_path_buf(),
+            token: rand::thread_rng().gen::<[u8; 32]>().to_hex(),
+        }
+    }
+
+    fn write_cookie_file(&self) -> Result<(), Box<dyn Error>> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Only readable by the user running the daemon
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(&self.cookie_file)?;
+        file.write_all(format!("{}:{}", COOKIE_USER, self.token).as_bytes())?;
+
+        Ok(())
+    }
+
+    pub async fn serve(&self, address: &str) -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind(address).await?;
+        self.write_cookie_file()?;
+        log::info!(
+            "listening on {}, cookie in {}",
+            listener.local_addr()?,
+            self.cookie_file.display()
+        );
+
+        // The connections are read and answered by their own tasks, which send the requests
+        // back here
+        let (sender, mut requests) = mpsc::channel(16);
+        let credentials = Arc::new(format!("{}:{}", COOKIE_USER, self.token));
+        task::spawn(accept_connections(listener, credentials, sender));
+
+        while let Some(request) = requests.recv().await {
+            let response = self.handle_body(&request.body).await?;
+            // The client may have given up already
+            let _ = request.response.send(response);
+        }
+
+        Err("the listener stopped".into())
+    }
+
+    async fn handle_body(&self, body: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let response = match serde_json::from_slice::<Request>(body) {
+            Ok(request) => {
+                let result = self.handle_request(&request.method, request.params).await;
+                // The code is only valid for the request it came with
+                self.resolver.clear();
+
+                match result {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+                    Err(e) => json!({
+                        "jsonrpc": "2.0",
+                        "id": request.id,
+                        "error": { "code": -1, "message": e.to_string() },
+                    }),
+                }
+            }
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": e.to_string() },
+            }),
+        };
+
+        Ok(serde_json::to_vec(&response)?)
+    }
+
+    async fn handle_request(&self, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+        // Positional parameters are not supported, a missing object means the defaults
+        let params = match params {
+            Value::Null => json!({}),
+            params => params,
+        };
+
+        match method {
+            "getbalance" => {
+                let subaccount = self.get_synced_subaccount(serde_json::from_value(params)?)?;
+                Ok(json!(subaccount.get_balance()?))
+            }
+            "getnewaddress" => {
+                let params: SubaccountParams = serde_json::from_value(params)?;
+                let subaccount = self.get_subaccount(params.subaccount)?;
+                // The address validator waits for the server
+                let address = task::block_in_place(|| subaccount.get_new_address())?;
+                Ok(json!(address.to_string()))
+            }
+            "listtransactions" => {
+                let subaccount = self.get_synced_subaccount(serde_json::from_value(params)?)?;
+                Ok(serde_json::to_value(subaccount.get_history(false).await?)?)
+            }
+            "listunspent" => {
+                let subaccount = self.get_synced_subaccount(serde_json::from_value(params)?)?;
+                let utxos = subaccount
+                    .list_unspent_with_status()?
+                    .into_iter()
+                    .map(|(utxo, status)| UnspentOutput {
+                        outpoint: utxo.outpoint,
+                        value: utxo.txout.value,
+                        status,
+                    })
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_value(utxos)?)
+            }
+            // Balances as of the last sync, `getbalance` syncs a single subaccount
+            "listsubaccounts" => Ok(serde_json::to_value(self.wallet.list_subaccounts(true)?)?),
+            "requesttwofactorcode" => {
+                let params: RequestCodeParams = serde_json::from_value(params)?;
+                self.wallet
+                    .get_session()
+                    .request_2fa_code(params.method, TwoFactorAction::SendRawTx)
+                    .await?;
+                Ok(Value::Null)
+            }
+            "send" => self.send(serde_json::from_value(params)?).await,
+            method => Err(format!("unknown method `{}`", method).into()),
+        }
+    }
+
+    async fn send(&self, params: SendParams) -> Result<Value, Box<dyn Error>> {
+        let session = self.wallet.get_session();
+        let subaccount = self.get_synced_subaccount(SubaccountParams {
+            subaccount: params.subaccount,
+        })?;
+
+        let address = Address::from_str(&params.address)?;
+        if address.network != self.network {
+            return Err("the recipient address is for the wrong network".into());
+        }
+        if let Some(amount) = params.amount {
+            if amount < get_dust_limit(&address.script_pubkey()) {
+                return Err("the amount is below the dust limit".into());
+            }
+        }
+
+        let min_fee_rate = session.get_min_fee_rate();
+        let fee_rate = match (params.fee_rate, params.fee_target) {
+            (Some(fee_rate), _) if fee_rate < min_fee_rate.as_sat_vb() => {
+                return Err("the fee rate is below the server minimum".into())
+            }
+            (Some(fee_rate), _) => bdk::FeeRate::from_sat_per_vb(fee_rate),
+            (None, Some(target)) => session.get_fee_rate(FeeTarget::from_str(&target)?),
+            (None, None) => session.get_fee_rate(FeeTarget::Medium),
+        };
+
+        let coin_control = subaccount.get_coin_control(&[])?;
+        let mut builder =
+            TxBuilder::with_recipients(vec![(address.script_pubkey(), params.amount.unwrap_or(0))])
+                .unspendable(coin_control.unspendable)
+                .enable_rbf()
+                .fee_rate(fee_rate);
+        if params.amount.is_none() {
+            builder = builder.send_all();
+        }
+        let (psbt, details) = subaccount.create_tx(builder)?;
+
+        if let Some(twofactor) = params.twofactor {
+            self.resolver.set(twofactor.method, twofactor.code);
+        }
+        // The service signer waits for the server, and broadcasting waits for the backend
+        let txid = task::block_in_place(|| -> Result<_, Box<dyn Error>> {
+            let (psbt, finalized) = subaccount.sign(psbt, None)?;
+            if !finalized {
+                return Err("unable to finalize the transaction".into());
+            }
+
+            Ok(subaccount.broadcast(psbt.extract_tx())?)
+        })?;
+
+        Ok(json!({ "txid": txid, "fee": details.fees }))
+    }
+
+    fn get_subaccount(&self, pointer: u16) -> Result<&Subaccount<B, D>, Box<dyn Error>> {
+        Ok(self
+            .wallet
+            .get_subaccount(pointer)
+            .ok_or_else(|| format!("unknown subaccount {}", pointer))?)
+    }
+
+    fn get_synced_subaccount(
+        &self,
+        params: SubaccountParams,
+    ) -> Result<&Subaccount<B, D>, Box<dyn Error>> {
+        let subaccount = self.get_subaccount(params.subaccount)?;
+        // Syncing blocks on the backend, so the other tasks are moved off this thread. The
+        // subaccounts can't be sent to a blocking thread, they are not `Sync`.
+        task::block_in_place(|| subaccount.sync(noop_progress(), None))?;
+
+        Ok(subaccount)
+    }
+}
+
+/// Request read by a connection task, waiting to be served by [`Daemon::serve`]
+struct PendingRequest {
+    body: Vec<u8>,
+    response: oneshot::Sender<Vec<u8>>,
+}
+
+async fn accept_connections(
+    mut listener: TcpListener,
+    credentials: Arc<String>,
+    requests: mpsc::Sender<PendingRequest>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("unable to accept connections: {}", e);
+                return;
+            }
+        };
+
+        let credentials = Arc::clone(&credentials);
+        let requests = requests.clone();
+        task::spawn(async move {
+            if let Err(e) = handle_connection(stream, &credentials, requests).await {
+                log::warn!("error serving {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    credentials: &str,
+    mut requests: mpsc::Sender<PendingRequest>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Headers included, so that a client can't keep sending them forever
+    let mut stream = BufReader::new(stream.take(MAX_REQUEST_SIZE as u64));
+
+    let body = timeout(IO_TIMEOUT, read_request(&mut stream, credentials))
+        .await
+        .map_err(|_| "timed out reading the request")??;
+
+    let (sender, receiver) = oneshot::channel();
+    requests
+        .send(PendingRequest {
+            body,
+            response: sender,
+        })
+        .await
+        .map_err(|_| "the daemon stopped")?;
+    // Waits for the requests before this one, so only the I/O has a timeout
+    let response = receiver.await.map_err(|_| "the daemon stopped")?;
+
+    timeout(
+        IO_TIMEOUT,
+        write_response(stream.get_mut().get_mut(), "200 OK", &response),
+    )
+    .await
+    .map_err(|_| "timed out writing the response")?
+}
+
+/// Read the headers and the body of a request, rejecting the unauthorized ones
+async fn read_request(
+    stream: &mut BufReader<Take<TcpStream>>,
+    credentials: &str,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let (mut content_length, mut content_type, mut authorization, mut has_origin) =
+        (None, None, None, false);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err("connection closed".into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            match name.as_str() {
+                "content-length" => content_length = Some(value.parse::<usize>()?),
+                "content-type" => content_type = Some(value.to_string()),
+                "authorization" => authorization = Some(value.to_string()),
+                "origin" => has_origin = true,
+                _ => {}
+            }
+        }
+    }
+
+    // Browsers always send the origin of cross-site requests, and can't send JSON to another
+    // site without a preflight request
+    let is_json = content_type
+        .as_deref()
+        .and_then(|value| value.split(';').next())
+        .map_or(false, |mime| {
+            mime.trim().eq_ignore_ascii_case("application/json")
+        });
+    let rejection = if !is_authorized(authorization.as_deref(), credentials) {
+        Some("401 Unauthorized")
+    } else if has_origin {
+        Some("403 Forbidden")
+    } else if !is_json {
+        Some("415 Unsupported Media Type")
+    } else {
+        None
+    };
+    if let Some(status) = rejection {
+        write_response(stream.get_mut().get_mut(), status, &[]).await?;
+        return Err(format!("request rejected with {}", status).into());
+    }
+
+    let content_length = content_length.ok_or("missing content length")?;
+    if content_length > MAX_REQUEST_SIZE {
+        return Err("request too big".into());
+    }
+    let mut body = vec![0u8; content_length];
+    stream.read_exact(&mut body).await?;
+
+    Ok(body)
+}
+
+fn is_authorized(authorization: Option<&str>, expected: &str) -> bool {
+    let credentials = authorization
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok());
+
+    // Compare in constant time, to not leak the token through the response time
+    match credentials {
+        Some(credentials) if credentials.len() == expected.len() => {
+            credentials
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    Ok(())
+}
@@ -0,0 +1,809 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::Rng;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use bdk::bitcoin;
+use bdk::sled;
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::util::bip32::Fingerprint;
+use bitcoin::{Network, OutPoint, Script, Transaction, TxOut, Txid};
+
+use bdk::database::{BatchDatabase, BatchOperations, Database};
+use bdk::{ScriptType, TransactionDetails, UTXO};
+
+use sled::{Batch, Db, Tree};
+
+/// Tree with the salt of the key derivation, only present in encrypted databases
+const ENCRYPTION_TREE: &str = "neerg-encryption";
+const KDF_ITERATIONS: u32 = 100_000;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone)]
+pub enum DatabaseConfig {
+    Sled {
+        path: PathBuf,
+    },
+    /// Sled database whose keys and values are encrypted with a key derived from `passphrase`
+    EncryptedSled {
+        path: PathBuf,
+        passphrase: String,
+    },
+    /// Nothing is persisted, every run starts with a full sync
+    Memory,
+    // TODO: SQLite, once BDK has a backend for it
+}
+
+/// Name of the tree of a subaccount, so that different wallets and networks can share a database
+pub fn get_namespace(network: Network, fingerprint: Fingerprint, pointer: u16) -> String {
+    format!("{}-{}-{}", network, fingerprint, pointer)
+}
+
+/// Name of the tree of the [`LocalStore`] of the subaccount stored in `namespace`
+pub fn get_local_namespace(namespace: &str) -> String {
+    format!("{}-local", namespace)
+}
+
+/// Data of a subaccount that BDK doesn't store, like the local coin control
+///
+/// It's kept in its own tree of the wallet database, so that it's also encrypted with it, or in
+/// memory when the wallet isn't persisted. Values are stored as JSON.
+#[derive(Debug)]
+pub enum LocalStore {
+    Sled(Tree),
+    Encrypted(EncryptedTree),
+    Memory(Mutex<HashMap<String, Vec<u8>>>),
+}
+
+impl LocalStore {
+    pub fn memory() -> Self {
+        LocalStore::Memory(Mutex::new(HashMap::new()))
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Box<dyn Error>> {
+        let value = match self {
+            LocalStore::Sled(tree) => tree.get(key)?.map(|value| value.to_vec()),
+            LocalStore::Encrypted(tree) => tree.get(&MapKey::Local(key).as_map_key())?,
+            LocalStore::Memory(entries) => entries.lock().unwrap().get(key).cloned(),
+        };
+
+        Ok(match value {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        })
+    }
+
+    /// Store `value` under `key`, `None` removes it
+    pub fn set<T: Serialize>(&self, key: &str, value: Option<&T>) -> Result<(), Box<dyn Error>> {
+        let value = match value {
+            Some(value) => Some(serde_json::to_vec(value)?),
+            None => None,
+        };
+
+        match (self, value) {
+            (LocalStore::Sled(tree), Some(value)) => {
+                tree.insert(key, value)?;
+            }
+            (LocalStore::Sled(tree), None) => {
+                tree.remove(key)?;
+            }
+            (LocalStore::Encrypted(tree), Some(value)) => {
+                tree.insert(&MapKey::Local(key).as_map_key(), &value)?;
+            }
+            (LocalStore::Encrypted(tree), None) => {
+                tree.remove(&MapKey::Local(key).as_map_key())?;
+            }
+            (LocalStore::Memory(entries), Some(value)) => {
+                entries.lock().unwrap().insert(key.to_string(), value);
+            }
+            (LocalStore::Memory(entries), None) => {
+                entries.lock().unwrap().remove(key);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Tree> for LocalStore {
+    fn from(tree: Tree) -> Self {
+        LocalStore::Sled(tree)
+    }
+}
+
+impl From<EncryptedTree> for LocalStore {
+    fn from(tree: EncryptedTree) -> Self {
+        LocalStore::Encrypted(tree)
+    }
+}
+
+/// Whether `db` was created by [`EncryptedSled`], so that it's never opened without passphrase
+pub fn is_encrypted(db: &Db) -> bool {
+    db.tree_names()
+        .iter()
+        .any(|name| name.as_ref() == ENCRYPTION_TREE.as_bytes())
+}
+
+/// Sled database that only stores encrypted data, with a key derived from a passphrase
+///
+/// Every value is encrypted together with its key, while keys and tree names are replaced by
+/// their HMAC, so neither the scripts nor the transactions of the wallet reach the disk in clear.
+/// Writes are persisted as they happen, like with a plain sled database.
+#[derive(Debug)]
+pub struct EncryptedSled {
+    db: Db,
+    keys: Arc<Keys>,
+}
+
+impl EncryptedSled {
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self, Box<dyn Error>> {
+        let db = sled::open(path)?;
+        // The default tree always exists
+        if !is_encrypted(&db) && db.tree_names().len() > 1 {
+            return Err("the database isn't encrypted".into());
+        }
+
+        let encryption = db.open_tree(ENCRYPTION_TREE)?;
+        let salt = match encryption.get("salt")? {
+            Some(salt) => salt.to_vec(),
+            None => {
+                let salt: [u8; 16] = rand::thread_rng().gen();
+                encryption.insert("salt", &salt[..])?;
+                salt.to_vec()
+            }
+        };
+        let keys = Keys::derive(passphrase, &salt);
+
+        match encryption.get("check")? {
+            Some(check) => match keys.open(&check) {
+                Ok((key, _)) if key == b"check" => {}
+                _ => return Err("wrong passphrase or corrupted database".into()),
+            },
+            None => {
+                encryption.insert("check", keys.seal(b"check", &[])?)?;
+            }
+        }
+
+        Ok(EncryptedSled {
+            db,
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn open_tree(&self, name: &str) -> Result<EncryptedTree, Box<dyn Error>> {
+        let tree = self
+            .db
+            .open_tree(self.keys.hash(name.as_bytes()).to_hex())?;
+
+        Ok(EncryptedTree {
+            tree,
+            keys: Arc::clone(&self.keys),
+        })
+    }
+}
+
+/// Keys derived from the passphrase: one encrypts the entries, the other hides their keys
+struct Keys {
+    cipher: ChaCha20Poly1305,
+    mac_key: [u8; 32],
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Keys(..)")
+    }
+}
+
+impl Keys {
+    fn derive(passphrase: &str, salt: &[u8]) -> Self {
+        let mut key = [0u8; 64];
+        pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(
+            passphrase.as_bytes(),
+            salt,
+            KDF_ITERATIONS,
+            &mut key,
+        );
+
+        let mut mac_key = [0u8; 32];
+        mac_key.copy_from_slice(&key[32..]);
+        Keys {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key[..32])),
+            mac_key,
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        let mut engine = HmacEngine::<sha256::Hash>::new(&self.mac_key);
+        engine.input(data);
+        Hmac::from_engine(engine).into_inner()
+    }
+
+    /// Encrypt `value` together with its `key`, so that entries can't be swapped on disk
+    fn seal(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>, bdk::Error> {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+
+        let mut plaintext = (key.len() as u32).to_le_bytes().to_vec();
+        plaintext.extend_from_slice(key);
+        plaintext.extend_from_slice(value);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| bdk::Error::Generic("unable to encrypt a database entry".into()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt an entry, returning its key and value
+    fn open(&self, sealed: &[u8]) -> Result<(Vec<u8>, Vec<u8>), bdk::Error> {
+        let invalid = || bdk::Error::Generic("corrupted database entry".into());
+
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| invalid())?;
+
+        let key_len = plaintext
+            .get(..4)
+            .and_then(|len| len.try_into().ok())
+            .map(|len| u32::from_le_bytes(len) as usize)
+            .ok_or_else(invalid)?;
+        if plaintext.len() < 4 + key_len {
+            return Err(invalid());
+        }
+        let (key, value) = plaintext[4..].split_at(key_len);
+
+        Ok((key.to_vec(), value.to_vec()))
+    }
+}
+
+const PATH_PREFIX: u8 = b'p';
+const SCRIPT_PREFIX: u8 = b's';
+const UTXO_PREFIX: u8 = b'u';
+const RAW_TX_PREFIX: u8 = b'r';
+const TX_PREFIX: u8 = b't';
+const LAST_INDEX_PREFIX: u8 = b'c';
+const CHECKSUM_PREFIX: u8 = b'd';
+const LOCAL_PREFIX: u8 = b'l';
+
+/// Key of an entry before it's hidden, the first byte is the type of the entry
+enum MapKey<'a> {
+    Path(ScriptType, u32),
+    Script(&'a Script),
+    Utxo(&'a OutPoint),
+    RawTx(&'a Txid),
+    Transaction(&'a Txid),
+    LastIndex(ScriptType),
+    DescriptorChecksum(ScriptType),
+    Local(&'a str),
+}
+
+impl MapKey<'_> {
+    fn as_map_key(&self) -> Vec<u8> {
+        let (prefix, data) = match self {
+            MapKey::Path(script_type, child) => (PATH_PREFIX, encode_path(*script_type, *child)),
+            MapKey::Script(script) => (SCRIPT_PREFIX, script.to_bytes()),
+            MapKey::Utxo(outpoint) => (UTXO_PREFIX, serialize(*outpoint)),
+            MapKey::RawTx(txid) => (RAW_TX_PREFIX, serialize(*txid)),
+            MapKey::Transaction(txid) => (TX_PREFIX, serialize(*txid)),
+            MapKey::LastIndex(script_type) => {
+                (LAST_INDEX_PREFIX, vec![script_type_byte(*script_type)])
+            }
+            MapKey::DescriptorChecksum(script_type) => {
+                (CHECKSUM_PREFIX, vec![script_type_byte(*script_type)])
+            }
+            MapKey::Local(key) => (LOCAL_PREFIX, key.as_bytes().to_vec()),
+        };
+
+        let mut key = vec![prefix];
+        key.extend(data);
+        key
+    }
+}
+
+fn script_type_byte(script_type: ScriptType) -> u8 {
+    match script_type {
+        ScriptType::External => b'e',
+        ScriptType::Internal => b'i',
+    }
+}
+
+fn encode_path(script_type: ScriptType, child: u32) -> Vec<u8> {
+    let mut path = vec![script_type_byte(script_type)];
+    path.extend_from_slice(&child.to_be_bytes());
+    path
+}
+
+fn decode_path(path: &[u8]) -> Result<(ScriptType, u32), bdk::Error> {
+    let script_type = match path.get(0) {
+        Some(b'e') => ScriptType::External,
+        Some(b'i') => ScriptType::Internal,
+        _ => return Err(bdk::Error::Generic("invalid script type".into())),
+    };
+
+    Ok((script_type, decode_u32(&path[1..])?))
+}
+
+fn decode_u32(value: &[u8]) -> Result<u32, bdk::Error> {
+    value
+        .try_into()
+        .map(u32::from_be_bytes)
+        .map_err(|_| bdk::Error::Generic("invalid index".into()))
+}
+
+fn decode_utxo(outpoint: OutPoint, value: &[u8]) -> Result<UTXO, bdk::Error> {
+    match value.split_last() {
+        Some((is_internal, txout)) => Ok(UTXO {
+            outpoint,
+            txout: deserialize::<TxOut>(txout)?,
+            is_internal: *is_internal == 1,
+        }),
+        None => Err(bdk::Error::Generic("invalid utxo".into())),
+    }
+}
+
+fn decode_tx(value: &[u8]) -> Result<TransactionDetails, bdk::Error> {
+    serde_json::from_slice(value).map_err(|e| bdk::Error::Generic(e.to_string()))
+}
+
+/// Tree of an [`EncryptedSled`], usable as a BDK database
+#[derive(Debug, Clone)]
+pub struct EncryptedTree {
+    tree: Tree,
+    keys: Arc<Keys>,
+}
+
+impl EncryptedTree {
+    /// The type of the entry is left in clear, so that the entries of a type can be listed
+    fn storage_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut storage_key = vec![key[0]];
+        storage_key.extend_from_slice(&self.keys.hash(key));
+        storage_key
+    }
+
+    fn open_entry(&self, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, bdk::Error> {
+        match self.keys.open(sealed)? {
+            (stored_key, value) if stored_key == key => Ok(value),
+            _ => Err(bdk::Error::Generic("corrupted database entry".into())),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, bdk::Error> {
+        match self.tree.get(self.storage_key(key))? {
+            Some(sealed) => Ok(Some(self.open_entry(key, &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), bdk::Error> {
+        self.tree
+            .insert(self.storage_key(key), self.keys.seal(key, value)?)?;
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, bdk::Error> {
+        match self.tree.remove(self.storage_key(key))? {
+            Some(sealed) => Ok(Some(self.open_entry(key, &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Keys and values of the entries of a type, sorted by key
+    fn scan(&self, prefix: u8) -> Result<Vec<(Vec<u8>, Vec<u8>)>, bdk::Error> {
+        let mut entries = self
+            .tree
+            .scan_prefix([prefix])
+            .map(|entry| self.keys.open(&entry?.1))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+
+        Ok(entries)
+    }
+}
+
+/// Writes to an [`EncryptedTree`], applied atomically when the batch is committed
+#[derive(Debug)]
+pub struct EncryptedBatch {
+    batch: Batch,
+    tree: EncryptedTree,
+}
+
+impl EncryptedBatch {
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), bdk::Error> {
+        self.batch
+            .insert(self.tree.storage_key(key), self.tree.keys.seal(key, value)?);
+
+        Ok(())
+    }
+
+    /// The previous value isn't known until the batch is applied
+    fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, bdk::Error> {
+        self.batch.remove(self.tree.storage_key(key));
+
+        Ok(None)
+    }
+}
+
+macro_rules! impl_batch_operations {
+    ($type:ty) => {
+        impl BatchOperations for $type {
+            fn set_script_pubkey(
+                &mut self,
+                script: &Script,
+                script_type: ScriptType,
+                child: u32,
+            ) -> Result<(), bdk::Error> {
+                self.insert(
+                    &MapKey::Path(script_type, child).as_map_key(),
+                    script.as_bytes(),
+                )?;
+                self.insert(
+                    &MapKey::Script(script).as_map_key(),
+                    &encode_path(script_type, child),
+                )
+            }
+
+            fn set_utxo(&mut self, utxo: &UTXO) -> Result<(), bdk::Error> {
+                let mut value = serialize(&utxo.txout);
+                value.push(utxo.is_internal as u8);
+
+                self.insert(&MapKey::Utxo(&utxo.outpoint).as_map_key(), &value)
+            }
+
+            fn set_raw_tx(&mut self, transaction: &Transaction) -> Result<(), bdk::Error> {
+                self.insert(
+                    &MapKey::RawTx(&transaction.txid()).as_map_key(),
+                    &serialize(transaction),
+                )
+            }
+
+            fn set_tx(&mut self, transaction: &TransactionDetails) -> Result<(), bdk::Error> {
+                // Like in the other BDK databases the raw transaction is stored on its own
+                let mut details = transaction.clone();
+                if let Some(tx) = details.transaction.take() {
+                    self.set_raw_tx(&tx)?;
+                }
+                let value =
+                    serde_json::to_vec(&details).map_err(|e| bdk::Error::Generic(e.to_string()))?;
+
+                self.insert(&MapKey::Transaction(&details.txid).as_map_key(), &value)
+            }
+
+            fn set_last_index(
+                &mut self,
+                script_type: ScriptType,
+                value: u32,
+            ) -> Result<(), bdk::Error> {
+                self.insert(
+                    &MapKey::LastIndex(script_type).as_map_key(),
+                    &value.to_be_bytes(),
+                )
+            }
+
+            fn del_script_pubkey_from_path(
+                &mut self,
+                script_type: ScriptType,
+                child: u32,
+            ) -> Result<Option<Script>, bdk::Error> {
+                let script = self.remove(&MapKey::Path(script_type, child).as_map_key())?;
+
+                Ok(script.map(Script::from))
+            }
+
+            fn del_path_from_script_pubkey(
+                &mut self,
+                script: &Script,
+            ) -> Result<Option<(ScriptType, u32)>, bdk::Error> {
+                self.remove(&MapKey::Script(script).as_map_key())?
+                    .map(|path| decode_path(&path))
+                    .transpose()
+            }
+
+            fn del_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<UTXO>, bdk::Error> {
+                self.remove(&MapKey::Utxo(outpoint).as_map_key())?
+                    .map(|utxo| decode_utxo(*outpoint, &utxo))
+                    .transpose()
+            }
+
+            fn del_raw_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, bdk::Error> {
+                let tx = self.remove(&MapKey::RawTx(txid).as_map_key())?;
+
+                Ok(tx.map(|tx| deserialize(&tx)).transpose()?)
+            }
+
+            fn del_tx(
+                &mut self,
+                txid: &Txid,
+                include_raw: bool,
+            ) -> Result<Option<TransactionDetails>, bdk::Error> {
+                let raw = match include_raw {
+                    true => self.del_raw_tx(txid)?,
+                    false => None,
+                };
+                let details = self
+                    .remove(&MapKey::Transaction(txid).as_map_key())?
+                    .map(|details| decode_tx(&details))
+                    .transpose()?;
+
+                Ok(details.map(|details| TransactionDetails {
+                    transaction: raw,
+                    ..details
+                }))
+            }
+
+            fn del_last_index(
+                &mut self,
+                script_type: ScriptType,
+            ) -> Result<Option<u32>, bdk::Error> {
+                self.remove(&MapKey::LastIndex(script_type).as_map_key())?
+                    .map(|index| decode_u32(&index))
+                    .transpose()
+            }
+        }
+    };
+}
+
+impl_batch_operations!(EncryptedTree);
+impl_batch_operations!(EncryptedBatch);
+
+impl Database for EncryptedTree {
+    fn check_descriptor_checksum<B: AsRef<[u8]>>(
+        &mut self,
+        script_type: ScriptType,
+        bytes: B,
+    ) -> Result<(), bdk::Error> {
+        let key = MapKey::DescriptorChecksum(script_type).as_map_key();
+
+        match self.get(&key)? {
+            Some(checksum) if checksum == bytes.as_ref() => Ok(()),
+            Some(_) => Err(bdk::Error::ChecksumMismatch),
+            None => self.insert(&key, bytes.as_ref()),
+        }
+    }
+
+    fn iter_script_pubkeys(
+        &self,
+        script_type: Option<ScriptType>,
+    ) -> Result<Vec<Script>, bdk::Error> {
+        let scripts = self
+            .scan(PATH_PREFIX)?
+            .into_iter()
+            .filter(|(key, _)| match script_type {
+                Some(script_type) => key.get(1) == Some(&script_type_byte(script_type)),
+                None => true,
+            })
+            .map(|(_, script)| Script::from(script))
+            .collect();
+
+        Ok(scripts)
+    }
+
+    fn iter_utxos(&self) -> Result<Vec<UTXO>, bdk::Error> {
+        self.scan(UTXO_PREFIX)?
+            .into_iter()
+            .map(|(key, utxo)| decode_utxo(deserialize(&key[1..])?, &utxo))
+            .collect()
+    }
+
+    fn iter_raw_txs(&self) -> Result<Vec<Transaction>, bdk::Error> {
+        self.scan(RAW_TX_PREFIX)?
+            .into_iter()
+            .map(|(_, tx)| Ok(deserialize(&tx)?))
+            .collect()
+    }
+
+    fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, bdk::Error> {
+        self.scan(TX_PREFIX)?
+            .into_iter()
+            .map(|(_, details)| {
+                let mut details = decode_tx(&details)?;
+                if include_raw {
+                    details.transaction = self.get_raw_tx(&details.txid)?;
+                }
+
+                Ok(details)
+            })
+            .collect()
+    }
+
+    fn get_script_pubkey_from_path(
+        &self,
+        script_type: ScriptType,
+        child: u32,
+    ) -> Result<Option<Script>, bdk::Error> {
+        let script = self.get(&MapKey::Path(script_type, child).as_map_key())?;
+
+        Ok(script.map(Script::from))
+    }
+
+    fn get_path_from_script_pubkey(
+        &self,
+        script: &Script,
+    ) -> Result<Option<(ScriptType, u32)>, bdk::Error> {
+        self.get(&MapKey::Script(script).as_map_key())?
+            .map(|path| decode_path(&path))
+            .transpose()
+    }
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<UTXO>, bdk::Error> {
+        self.get(&MapKey::Utxo(outpoint).as_map_key())?
+            .map(|utxo| decode_utxo(*outpoint, &utxo))
+            .transpose()
+    }
+
+    fn get_raw_tx(&self, txid: &Txid) -> Result<Option<Transaction>, bdk::Error> {
+        let tx = self.get(&MapKey::RawTx(txid).as_map_key())?;
+
+        Ok(tx.map(|tx| deserialize(&tx)).transpose()?)
+    }
+
+    fn get_tx(
+        &self,
+        txid: &Txid,
+        include_raw: bool,
+    ) -> Result<Option<TransactionDetails>, bdk::Error> {
+        let details = self
+            .get(&MapKey::Transaction(txid).as_map_key())?
+            .map(|details| decode_tx(&details))
+            .transpose()?;
+
+        match (details, include_raw) {
+            (Some(details), true) => Ok(Some(TransactionDetails {
+                transaction: self.get_raw_tx(txid)?,
+                ..details
+            })),
+            (details, _) => Ok(details),
+        }
+    }
+
+    fn get_last_index(&self, script_type: ScriptType) -> Result<Option<u32>, bdk::Error> {
+        self.get(&MapKey::LastIndex(script_type).as_map_key())?
+            .map(|index| decode_u32(&index))
+            .transpose()
+    }
+
+    fn increment_last_index(&mut self, script_type: ScriptType) -> Result<u32, bdk::Error> {
+        let index = match self.get_last_index(script_type)? {
+            Some(last) => last + 1,
+            None => 0,
+        };
+        self.set_last_index(script_type, index)?;
+
+        Ok(index)
+    }
+}
+
+impl BatchDatabase for EncryptedTree {
+    type Batch = EncryptedBatch;
+
+    fn begin_batch(&self) -> Self::Batch {
+        EncryptedBatch {
+            batch: Batch::default(),
+            tree: self.clone(),
+        }
+    }
+
+    fn commit_batch(&mut self, batch: Self::Batch) -> Result<(), bdk::Error> {
+        Ok(self.tree.apply_batch(batch.batch)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use bitcoin::blockdata::script::Builder;
+
+    fn get_test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("neerg-db-test-{}-{}", name, std::process::id()))
+    }
+
+    /// Whether any file written by sled in `path` contains `data`
+    fn is_on_disk(path: &Path, data: &[u8]) -> bool {
+        fs::read_dir(path).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => is_on_disk(&path, data),
+                false => fs::read(&path)
+                    .unwrap()
+                    .windows(data.len())
+                    .any(|window| window == data),
+            }
+        })
+    }
+
+    #[test]
+    fn test_encrypted_reopen() {
+        let path = get_test_path("reopen");
+        let namespace = "testnet-01020304-1";
+        let script = Builder::new()
+            .push_slice(b"neerg test script")
+            .into_script();
+
+        {
+            let db = EncryptedSled::open(&path, "passphrase").unwrap();
+            let mut tree = db.open_tree(namespace).unwrap();
+            tree.set_script_pubkey(&script, ScriptType::External, 7)
+                .unwrap();
+
+            let mut batch = tree.begin_batch();
+            batch.set_last_index(ScriptType::External, 7).unwrap();
+            tree.commit_batch(batch).unwrap();
+
+            let local = LocalStore::from(db.open_tree(&get_local_namespace(namespace)).unwrap());
+            local.set("label", Some(&"neerg test label")).unwrap();
+        }
+
+        assert!(!is_on_disk(&path, namespace.as_bytes()));
+        assert!(!is_on_disk(&path, script.as_bytes()));
+        assert!(!is_on_disk(&path, b"neerg test label"));
+
+        let db = EncryptedSled::open(&path, "passphrase").unwrap();
+        let tree = db.open_tree(namespace).unwrap();
+        assert_eq!(
+            tree.get_path_from_script_pubkey(&script).unwrap(),
+            Some((ScriptType::External, 7))
+        );
+        assert_eq!(tree.iter_script_pubkeys(None).unwrap(), vec![script]);
+        assert_eq!(tree.get_last_index(ScriptType::External).unwrap(), Some(7));
+
+        let local = LocalStore::from(db.open_tree(&get_local_namespace(namespace)).unwrap());
+        assert_eq!(
+            local.get::<String>("label").unwrap(),
+            Some("neerg test label".to_string())
+        );
+
+        drop(db);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_wrong_passphrase() {
+        let path = get_test_path("passphrase");
+        drop(EncryptedSled::open(&path, "passphrase").unwrap());
+
+        let err = EncryptedSled::open(&path, "wrong passphrase").unwrap_err();
+        assert_eq!(err.to_string(), "wrong passphrase or corrupted database");
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_plain_database_is_not_encrypted() {
+        let path = get_test_path("plain");
+        {
+            let db = sled::open(&path).unwrap();
+            db.open_tree("testnet-01020304-0")
+                .unwrap()
+                .insert("key", "value")
+                .unwrap();
+            assert!(!is_encrypted(&db));
+        }
+
+        let err = EncryptedSled::open(&path, "passphrase").unwrap_err();
+        assert_eq!(err.to_string(), "the database isn't encrypted");
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -1,18 +1,24 @@
 // From: https://github.com/afilini/gadescriptor
 
+use std::error::Error;
 use std::ops::Deref;
 use std::str::FromStr;
 
 use bdk::bitcoin;
+use bdk::descriptor::checksum::get_checksum;
 use bdk::descriptor::{Descriptor, KeyMap, ToWalletDescriptor};
 use bdk::keys::KeyError;
 
-use bdk::miniscript::descriptor::DescriptorPublicKey;
+use bdk::miniscript::descriptor::{DescriptorPublicKey, DescriptorXKey};
 
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
 use bitcoin::hashes::hex::FromHex;
 use bitcoin::secp256k1::Secp256k1;
-use bitcoin::util::bip32::{ChainCode, ChildNumber, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
-use bitcoin::{Network, PublicKey};
+use bitcoin::util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
+use bitcoin::{Address, Network, PublicKey};
+
+use crate::signer::UserSigner;
 
 lazy_static! {
     static ref GA_TESTNET: ExtendedPubKey = ExtendedPubKey {
@@ -47,19 +53,51 @@ lazy_static! {
 
 #[derive(Debug)]
 pub struct GreenSubaccountDescriptor<'a> {
-    pub xprv: &'a ExtendedPrivKey,
+    pub user_fingerprint: Fingerprint,
+    /// User xpub derived at [`GreenSubaccountDescriptor::get_user_path`]
+    pub user_xpub: ExtendedPubKey,
     pub gait_path: &'a Vec<u16>,
     pub subaccount: Option<u16>,
 }
 
 impl<'a> GreenSubaccountDescriptor<'a> {
+    pub fn from_signer(
+        signer: &dyn UserSigner,
+        gait_path: &'a Vec<u16>,
+        pointer: u16,
+    ) -> Result<Self, Box<dyn Error>> {
+        let subaccount = match pointer {
+            0 => None,
+            p => Some(p),
+        };
+
+        Ok(GreenSubaccountDescriptor {
+            user_fingerprint: signer.get_fingerprint()?,
+            user_xpub: signer.get_xpub(&Self::get_user_path(subaccount))?,
+            gait_path,
+            subaccount,
+        })
+    }
+
+    pub fn get_user_path(subaccount: Option<u16>) -> DerivationPath {
+        match subaccount {
+            None => vec![],
+            Some(pointer) => vec![
+                ChildNumber::Hardened { index: 3 },
+                ChildNumber::Hardened {
+                    index: pointer as u32,
+                },
+            ],
+        }
+        .into()
+    }
+
     fn get_derived_service_xpub(&self, network: Network) -> ExtendedPubKey {
         let ctx = Secp256k1::new();
 
         let ga_xpub = match network {
             Network::Bitcoin => GA_MAINNET.deref(),
-            Network::Testnet => GA_TESTNET.deref(),
-            _ => unimplemented!(),
+            Network::Testnet | Network::Regtest => GA_TESTNET.deref(),
         };
 
         let full_path = match self.subaccount {
@@ -88,6 +126,47 @@ impl<'a> GreenSubaccountDescriptor<'a> {
     pub fn get_service_fingerprint(&self, network: Network) -> Fingerprint {
         self.get_derived_service_xpub(network).fingerprint()
     }
+
+    /// Path of the user key of the address at `index`
+    pub fn get_user_key_path(&self, index: u32) -> DerivationPath {
+        Self::get_user_path(self.subaccount)
+            .child(ChildNumber::Normal { index: 1 })
+            .child(ChildNumber::Normal { index })
+    }
+
+    /// Address at `index`, the same derived by the wallet from the descriptor
+    pub fn get_address(&self, index: u32, network: Network) -> Result<Address, Box<dyn Error>> {
+        let ctx = Secp256k1::verification_only();
+        let child = ChildNumber::from_normal_idx(index)?;
+
+        let service_key = self
+            .get_derived_service_xpub(network)
+            .derive_pub(&ctx, &[child])?
+            .public_key;
+        let user_key = self
+            .user_xpub
+            .derive_pub(&ctx, &[ChildNumber::Normal { index: 1 }, child])?
+            .public_key;
+
+        let witness_script = Builder::new()
+            .push_int(2)
+            .push_key(&service_key)
+            .push_key(&user_key)
+            .push_int(2)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+
+        Ok(Address::p2shwsh(&witness_script, network))
+    }
+
+    /// Public descriptor with its checksum, in the format accepted by Bitcoin Core
+    pub fn to_descriptor_string(self, network: Network) -> Result<String, Box<dyn Error>> {
+        let (descriptor, _) = self.to_wallet_descriptor(network)?;
+        let descriptor = descriptor.to_string();
+        let checksum = get_checksum(&descriptor)?;
+
+        Ok(format!("{}#{}", descriptor, checksum))
+    }
 }
 
 impl<'a> ToWalletDescriptor for GreenSubaccountDescriptor<'a> {
@@ -97,19 +176,14 @@ impl<'a> ToWalletDescriptor for GreenSubaccountDescriptor<'a> {
     ) -> Result<(Descriptor<DescriptorPublicKey>, KeyMap), KeyError> {
         let derived_service_xpub = self.get_derived_service_xpub(network);
 
-        let mut path = match self.subaccount {
-            None => vec![],
-            Some(pointer) => vec![
-                ChildNumber::Hardened { index: 3 },
-                ChildNumber::Hardened {
-                    index: pointer as u32,
-                },
-            ],
-        };
-        path.push(ChildNumber::Normal { index: 1 });
-
         let service_key = (derived_service_xpub, vec![].into());
-        let user_key = (self.xprv.clone(), path.into());
+        // Keep the origin of the user key so that signers can recognize it in the PSBTs
+        let user_key = DescriptorPublicKey::XPub(DescriptorXKey {
+            source: Some((self.user_fingerprint, Self::get_user_path(self.subaccount))),
+            xkey: self.user_xpub,
+            derivation_path: vec![ChildNumber::Normal { index: 1 }].into(),
+            is_wildcard: true,
+        });
 
         let descriptor = descriptor!(sh ( wsh ( multi 2, service_key, user_key ) ))?;
         Ok((descriptor.0, descriptor.1))
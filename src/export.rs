@@ -0,0 +1,372 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use bdk::bitcoin;
+
+use bitcoin::{Address, Network, Txid};
+
+use bdk::blockchain::Blockchain;
+use bdk::database::BatchDatabase;
+
+use crate::descriptor::GreenSubaccountDescriptor;
+use crate::fiat::FiatRate;
+use crate::ga::GAClient;
+use crate::signer::UserSigner;
+use crate::subaccount::Subaccount;
+
+/// Single entry of Bitcoin Core's `importdescriptors`
+#[derive(Debug, Serialize)]
+pub struct CoreImportDescriptor {
+    pub desc: String,
+    pub timestamp: u64,
+    pub active: bool,
+    pub range: (u32, u32),
+    pub watchonly: bool,
+    pub label: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubaccountDescriptor {
+    pub pointer: u16,
+    pub name: String,
+    pub descriptor: String,
+}
+
+/// Public descriptors of the main account and every subaccount known by the server
+pub fn get_descriptors(
+    signer: &dyn UserSigner,
+    session: &GAClient,
+    network: Network,
+) -> Result<Vec<SubaccountDescriptor>, Box<dyn Error>> {
+    let main_account = (0, "Main Account".to_string());
+    let subaccounts = session
+        .get_subaccounts()
+        .into_iter()
+        .map(|s| (s.pointer, s.name));
+
+    std::iter::once(main_account)
+        .chain(subaccounts)
+        .map(|(pointer, name)| -> Result<_, Box<dyn Error>> {
+            let desc =
+                GreenSubaccountDescriptor::from_signer(signer, &session.get_gait_path(), pointer)?;
+
+            Ok(SubaccountDescriptor {
+                pointer,
+                name,
+                descriptor: desc.to_descriptor_string(network)?,
+            })
+        })
+        .collect()
+}
+
+/// Export the descriptors in the format expected by Bitcoin Core's `importdescriptors`, so that
+/// they can be imported in a watch-only wallet
+pub fn export_core_descriptors(
+    descriptors: &[SubaccountDescriptor],
+    timestamp: u64,
+    range: u32,
+) -> Result<String, Box<dyn Error>> {
+    let requests = descriptors
+        .iter()
+        .map(|d| CoreImportDescriptor {
+            desc: d.descriptor.clone(),
+            timestamp,
+            active: false,
+            range: (0, range),
+            watchonly: true,
+            label: d.name.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(serde_json::to_string_pretty(&requests)?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for HistoryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(HistoryFormat::Csv),
+            "json" => Ok(HistoryFormat::Json),
+            _ => Err(format!("unknown history format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryOutput {
+    pub vout: u32,
+    /// `None` for scripts that don't have an address, like `OP_RETURN`
+    pub address: Option<String>,
+    pub value: u64,
+    pub is_mine: bool,
+}
+
+/// Transaction of a subaccount as exported for accounting
+#[derive(Debug, Serialize)]
+pub struct HistoryRecord {
+    pub txid: Txid,
+    /// Unix time in seconds when the server first saw the transaction, the local one is only
+    /// used for transactions the server doesn't know
+    pub timestamp: u64,
+    pub height: Option<u32>,
+    pub confirmations: u32,
+    pub received: u64,
+    pub sent: u64,
+    /// Change in the balance of the subaccount, including the fee
+    pub net: i64,
+    pub fee: u64,
+    pub memo: String,
+    /// `net` converted at the rate used for the export
+    pub fiat_net: Option<f64>,
+    pub fiat_currency: Option<String>,
+    pub outputs: Vec<HistoryOutput>,
+}
+
+/// Build the history of a subaccount from the local wallet and the memos stored on the server
+///
+/// `tip_height` is used to compute the confirmations, which are left to zero when unknown. The
+/// server only provides the current fiat rate, so every amount is converted with `fiat_rate`
+/// rather than the rate at the time of the transaction.
+pub async fn get_history_records<B: Blockchain, D: BatchDatabase>(
+    subaccount: &Subaccount<B, D>,
+    network: Network,
+    tip_height: Option<u32>,
+    fiat_rate: Option<&FiatRate>,
+) -> Result<Vec<HistoryRecord>, Box<dyn Error>> {
+    let mut records = subaccount
+        .get_history(true)
+        .await?
+        .into_iter()
+        .map(|entry| -> Result<_, Box<dyn Error>> {
+            let details = entry.details;
+            let outputs = details
+                .transaction
+                .as_ref()
+                .ok_or("missing raw transaction")?
+                .output
+                .iter()
+                .enumerate()
+                .map(|(vout, output)| -> Result<_, Box<dyn Error>> {
+                    Ok(HistoryOutput {
+                        vout: vout as u32,
+                        address: Address::from_script(&output.script_pubkey, network)
+                            .map(|a| a.to_string()),
+                        value: output.value,
+                        is_mine: subaccount.is_mine(&output.script_pubkey)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let confirmations = match (details.height, tip_height) {
+                (Some(height), Some(tip)) if tip >= height => tip - height + 1,
+                _ => 0,
+            };
+
+            let net = details.received as i64 - details.sent as i64;
+            let fiat_net = fiat_rate.map(|rate| rate.to_fiat(net));
+
+            Ok(HistoryRecord {
+                txid: details.txid,
+                timestamp: entry.created_at.unwrap_or(details.timestamp),
+                height: details.height,
+                confirmations,
+                received: details.received,
+                sent: details.sent,
+                net,
+                fee: details.fees,
+                memo: entry.memo.unwrap_or_default(),
+                fiat_net: fiat_net.as_ref().map(|f| f.amount),
+                fiat_currency: fiat_net.map(|f| f.currency),
+                outputs,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Unconfirmed transactions last
+    records.sort_by_key(|r| (r.height.unwrap_or(std::u32::MAX), r.timestamp));
+
+    Ok(records)
+}
+
+pub fn export_history(
+    records: &[HistoryRecord],
+    format: HistoryFormat,
+) -> Result<String, Box<dyn Error>> {
+    match format {
+        HistoryFormat::Json => Ok(serde_json::to_string_pretty(records)?),
+        HistoryFormat::Csv => Ok(export_history_csv(records)),
+    }
+}
+
+/// One row per output, the transaction columns are repeated on every row
+///
+/// Text fields are always quoted, and the ones that a spreadsheet would run as a formula are
+/// prefixed with `'`, since memos and addresses can come from anyone.
+fn export_history_csv(records: &[HistoryRecord]) -> String {
+    fn escape(field: &str) -> String {
+        let field = match field.starts_with(|c| "=+-@\t\r".contains(c)) {
+            true => format!("'{}", field),
+            false => field.to_string(),
+        };
+
+        format!("\"{}\"", field.replace('"', "\"\""))
+    }
+
+    let mut csv = String::from(
+        "txid,timestamp,height,confirmations,received,sent,net,fee,memo,fiat_net,fiat_currency,vout,address,value,is_mine\n",
+    );
+    for record in records {
+        for output in &record.outputs {
+            let row = [
+                escape(&record.txid.to_string()),
+                record.timestamp.to_string(),
+                record.height.map(|h| h.to_string()).unwrap_or_default(),
+                record.confirmations.to_string(),
+                record.received.to_string(),
+                record.sent.to_string(),
+                record.net.to_string(),
+                record.fee.to_string(),
+                escape(&record.memo),
+                record
+                    .fiat_net
+                    .map(|amount| format!("{:.2}", amount))
+                    .unwrap_or_default(),
+                escape(record.fiat_currency.as_deref().unwrap_or_default()),
+                output.vout.to_string(),
+                escape(output.address.as_deref().unwrap_or_default()),
+                output.value.to_string(),
+                output.is_mine.to_string(),
+            ];
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::hashes::hex::FromHex;
+
+    fn get_record(memo: &str, address: Option<&str>) -> HistoryRecord {
+        HistoryRecord {
+            txid: Txid::from_hex(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            )
+            .unwrap(),
+            timestamp: 1_600_000_000,
+            height: Some(100),
+            confirmations: 6,
+            received: 10_000,
+            sent: 0,
+            net: 10_000,
+            fee: 0,
+            memo: memo.to_string(),
+            fiat_net: Some(1.5),
+            fiat_currency: Some("EUR".to_string()),
+            outputs: vec![
+                HistoryOutput {
+                    vout: 0,
+                    address: address.map(str::to_string),
+                    value: 10_000,
+                    is_mine: true,
+                },
+                HistoryOutput {
+                    vout: 1,
+                    address: None,
+                    value: 0,
+                    is_mine: false,
+                },
+            ],
+        }
+    }
+
+    fn get_csv_rows(records: &[HistoryRecord]) -> Vec<String> {
+        export_history(records, HistoryFormat::Csv)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_formula_injection() {
+        for prefix in &["=", "+", "-", "@", "\t", "\r"] {
+            let memo = format!("{}HYPERLINK(\"http://evil\")", prefix);
+            let rows = get_csv_rows(&[get_record(&memo, Some(&memo))]);
+
+            let escaped = format!("\"'{}HYPERLINK(\"\"http://evil\"\")\"", prefix);
+            assert_eq!(rows[0].matches(&escaped).count(), 2, "prefix {:?}", prefix);
+        }
+
+        // Only the first character matters
+        let rows = get_csv_rows(&[get_record("pay =1+1", None)]);
+        assert!(rows[0].contains(",\"pay =1+1\","));
+    }
+
+    #[test]
+    fn test_csv_quoting() {
+        let rows = get_csv_rows(&[get_record(
+            "rent, \"march\"",
+            Some("2N8hwP1WmJrFF5QWABn38y63uYLhnJYJYTF"),
+        )]);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0],
+            "\"4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b\",1600000000,100,6,10000,0,10000,0,\"rent, \"\"march\"\"\",1.50,\"EUR\",0,\"2N8hwP1WmJrFF5QWABn38y63uYLhnJYJYTF\",10000,true"
+        );
+        // Outputs without an address, like `OP_RETURN`, have an empty one
+        assert!(rows[1].ends_with(",1,\"\",0,false"));
+    }
+
+    #[test]
+    fn test_json_output() {
+        let mut unconfirmed = get_record("", None);
+        unconfirmed.height = None;
+        unconfirmed.fiat_net = None;
+        unconfirmed.fiat_currency = None;
+
+        let json = export_history(
+            &[get_record("rent", None), unconfirmed],
+            HistoryFormat::Json,
+        )
+        .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            value[0],
+            serde_json::json!({
+                "txid": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "timestamp": 1_600_000_000u64,
+                "height": 100,
+                "confirmations": 6,
+                "received": 10_000,
+                "sent": 0,
+                "net": 10_000,
+                "fee": 0,
+                "memo": "rent",
+                "fiat_net": 1.5,
+                "fiat_currency": "EUR",
+                "outputs": [
+                    { "vout": 0, "address": null, "value": 10_000, "is_mine": true },
+                    { "vout": 1, "address": null, "value": 0, "is_mine": false },
+                ],
+            })
+        );
+        assert_eq!(value[1]["height"], serde_json::Value::Null);
+        assert_eq!(value[1]["fiat_net"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bdk::FeeRate;
+
+use crate::types::FeeEstimate;
+
+/// Relay fee used by the server when it doesn't send one, in sat/kvB
+pub const DEFAULT_MIN_FEE: u64 = 1000;
+
+/// How soon a transaction should confirm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeTarget {
+    Fast,
+    Medium,
+    Slow,
+    Blocks(u32),
+}
+
+impl FeeTarget {
+    pub fn get_blocks(&self) -> u32 {
+        match self {
+            FeeTarget::Fast => 3,
+            FeeTarget::Medium => 12,
+            FeeTarget::Slow => 24,
+            FeeTarget::Blocks(blocks) => *blocks,
+        }
+    }
+}
+
+impl FromStr for FeeTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(FeeTarget::Fast),
+            "medium" => Ok(FeeTarget::Medium),
+            "slow" => Ok(FeeTarget::Slow),
+            blocks => match blocks.parse() {
+                Ok(0) | Err(_) => Err(format!(
+                    "invalid fee target `{}`, expected `fast`, `medium`, `slow` or a number of blocks",
+                    s
+                )),
+                Ok(blocks) => Ok(FeeTarget::Blocks(blocks)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FeeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeTarget::Fast => write!(f, "fast"),
+            FeeTarget::Medium => write!(f, "medium"),
+            FeeTarget::Slow => write!(f, "slow"),
+            FeeTarget::Blocks(blocks) => write!(f, "{} blocks", blocks),
+        }
+    }
+}
+
+/// Convert a fee rate in sat/kvB, the unit used by the server, to a `FeeRate`
+pub fn fee_rate_from_sat_per_kvb(sat_per_kvb: u64) -> FeeRate {
+    FeeRate::from_sat_per_vb(sat_per_kvb as f32 / 1000.0)
+}
+
+/// Pick the estimate of the slowest target that still confirms within `target`, never going
+/// below the minimum relay fee. Falls back to the fastest estimate when the target is shorter
+/// than all of them.
+pub fn select_fee_rate(
+    estimates: &HashMap<String, FeeEstimate>,
+    min_fee: u64,
+    target: FeeTarget,
+) -> FeeRate {
+    let blocks = target.get_blocks();
+    let mut valid = estimates
+        .values()
+        .filter(|e| e.feerate > 0.0)
+        .collect::<Vec<_>>();
+    valid.sort_by_key(|e| e.blocks);
+
+    let estimate = valid
+        .iter()
+        .rev()
+        .find(|e| e.blocks <= blocks)
+        .or_else(|| valid.first());
+
+    // The server sends BTC/kvB
+    let sat_per_kvb = estimate
+        .map(|e| (e.feerate * 100_000_000.0).round() as u64)
+        .unwrap_or(min_fee);
+
+    fee_rate_from_sat_per_kvb(std::cmp::max(sat_per_kvb, min_fee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Estimates in BTC/kvB by number of blocks, negative when missing
+    fn get_estimates(estimates: &[(u32, f64)]) -> HashMap<String, FeeEstimate> {
+        estimates
+            .iter()
+            .map(|&(blocks, feerate)| (blocks.to_string(), FeeEstimate { feerate, blocks }))
+            .collect()
+    }
+
+    #[test]
+    fn test_select_fee_rate() {
+        let estimates = get_estimates(&[(3, 0.0002), (12, 0.0001), (24, 0.00002)]);
+
+        let select = |target| select_fee_rate(&estimates, DEFAULT_MIN_FEE, target).as_sat_vb();
+        assert_eq!(select(FeeTarget::Fast), 20.0);
+        assert_eq!(select(FeeTarget::Medium), 10.0);
+        assert_eq!(select(FeeTarget::Slow), 2.0);
+        assert_eq!(select(FeeTarget::Blocks(6)), 20.0);
+        assert_eq!(select(FeeTarget::Blocks(144)), 2.0);
+    }
+
+    #[test]
+    fn test_min_fee_is_enforced() {
+        let estimates = get_estimates(&[(3, 0.0002), (24, 0.00002)]);
+
+        assert_eq!(
+            select_fee_rate(&estimates, 5000, FeeTarget::Slow).as_sat_vb(),
+            5.0
+        );
+        assert_eq!(
+            select_fee_rate(&estimates, 5000, FeeTarget::Fast).as_sat_vb(),
+            20.0
+        );
+    }
+
+    #[test]
+    fn test_missing_estimates() {
+        // No estimate for 12 blocks, so the next faster one is used
+        let estimates = get_estimates(&[(3, 0.0002), (12, -1.0), (24, 0.00002)]);
+        assert_eq!(
+            select_fee_rate(&estimates, DEFAULT_MIN_FEE, FeeTarget::Medium).as_sat_vb(),
+            20.0
+        );
+
+        // Shorter than every estimate, the fastest one is used
+        let estimates = get_estimates(&[(3, -1.0), (12, 0.0001)]);
+        assert_eq!(
+            select_fee_rate(&estimates, DEFAULT_MIN_FEE, FeeTarget::Fast).as_sat_vb(),
+            10.0
+        );
+
+        // Nothing at all, only the minimum relay fee is left
+        let estimates = get_estimates(&[(3, -1.0), (12, 0.0)]);
+        assert_eq!(
+            select_fee_rate(&estimates, DEFAULT_MIN_FEE, FeeTarget::Fast).as_sat_vb(),
+            1.0
+        );
+        assert_eq!(
+            select_fee_rate(&HashMap::new(), 2000, FeeTarget::Slow).as_sat_vb(),
+            2.0
+        );
+    }
+}
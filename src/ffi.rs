@@ -0,0 +1,534 @@
+//! C interface in the style of gdk's `GA_*` functions
+//!
+//! Every function returns `GA_OK` or `GA_ERROR`, arguments and results are JSON strings. The
+//! details of the last error of the calling thread are returned by
+//! `GA_get_thread_error_details`. Strings returned through `output` must be released with
+//! `GA_destroy_string`.
+//!
+//! The pointers passed to these functions must be either `NULL` or valid: sessions created by
+//! `GA_create_session` and not yet destroyed, and NUL-terminated strings.
+
+#![allow(non_camel_case_types, non_snake_case, clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use tokio::runtime::Runtime;
+
+use bip0039::{Language, Mnemonic, Seed};
+
+use bdk::bitcoin;
+use bdk::electrum_client::Client as ElectrumClient;
+use bdk::sled;
+
+use bitcoin::util::bip32::ExtendedPrivKey;
+use bitcoin::{Address, Network};
+
+use bdk::blockchain::{noop_progress, ElectrumBlockchain};
+use bdk::{FeeRate, TxBuilder};
+
+use crate::airgap::{psbt_from_base64, psbt_to_base64};
+use crate::batch::get_dust_limit;
+use crate::config::GAClientConfig;
+use crate::database::{get_local_namespace, get_namespace};
+use crate::fees::FeeTarget;
+use crate::ga::GAClient;
+use crate::signer::{SoftwareSigner, UserSigner};
+use crate::subaccount::Subaccount;
+use crate::twofactor::TwoFactorResolver;
+use crate::types::TwoFactorMethod;
+use crate::wallet::GreenWallet;
+
+pub const GA_OK: c_int = 0;
+pub const GA_ERROR: c_int = -1;
+
+/// Called to choose the 2FA method and to get the code
+///
+/// `request` is either `{"action": "get_method", "methods": [...]}`, answered with the JSON
+/// string of one of the methods, or `{"action": "get_code", "method": ...}`, answered with the
+/// JSON string of the code. The answer is written NUL-terminated in `response`, which can hold
+/// `response_len` bytes. Returns `GA_OK` on success.
+pub type GA_twofactor_callback = extern "C" fn(
+    context: *mut c_void,
+    request: *const c_char,
+    response: *mut c_char,
+    response_len: usize,
+) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SessionConfig {
+    endpoint: Option<String>,
+    proxy: Option<String>,
+    electrum_url: Option<String>,
+    database_path: Option<PathBuf>,
+    device_id_file: Option<PathBuf>,
+    /// Timeout of the calls to the Green server in seconds
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Callback {
+    function: GA_twofactor_callback,
+    // Opaque pointer owned by the caller, who guarantees it can be used from any thread
+    context: usize,
+}
+
+/// Resolver that forwards the 2FA requests to the callback registered by the caller
+#[derive(Debug, Default)]
+struct CallbackResolver {
+    callback: Mutex<Option<Callback>>,
+    /// Method chosen by the last `get_method`, sent along with the code request
+    method: Mutex<Option<TwoFactorMethod>>,
+}
+
+impl CallbackResolver {
+    fn call(&self, request: Value) -> Option<Value> {
+        let callback = (*self.callback.lock().unwrap())?;
+
+        let request = CString::new(request.to_string()).ok()?;
+        let mut response = vec![0u8; 1024];
+        let result = (callback.function)(
+            callback.context as *mut c_void,
+            request.as_ptr(),
+            response.as_mut_ptr() as *mut c_char,
+            response.len(),
+        );
+        if result != GA_OK {
+            return None;
+        }
+
+        let len = response.iter().position(|b| *b == 0)?;
+        serde_json::from_slice(&response[..len]).ok()
+    }
+}
+
+impl TwoFactorResolver for CallbackResolver {
+    fn get_method(&self, available: Vec<TwoFactorMethod>) -> TwoFactorMethod {
+        let request = json!({ "action": "get_method", "methods": available });
+        let method = self
+            .call(request)
+            .and_then(|method| serde_json::from_value(method).ok())
+            .or_else(|| available.into_iter().next())
+            .unwrap_or(TwoFactorMethod::Gauth);
+        *self.method.lock().unwrap() = Some(method);
+
+        method
+    }
+
+    fn get_code(&self) -> String {
+        let method = *self.method.lock().unwrap();
+
+        // An empty code is rejected by the server, which fails the signature
+        self.call(json!({ "action": "get_code", "method": method }))
+            .and_then(|code| serde_json::from_value(code).ok())
+            .unwrap_or_default()
+    }
+}
+
+pub struct GA_session {
+    runtime: Runtime,
+    config: SessionConfig,
+    resolver: Arc<CallbackResolver>,
+    wallet: Option<GreenWallet>,
+}
+
+impl GA_session {
+    fn get_wallet(&self) -> Result<&GreenWallet, Box<dyn Error>> {
+        Ok(self.wallet.as_ref().ok_or("not logged in")?)
+    }
+
+    fn get_subaccount(&self, details: &Value) -> Result<&Subaccount, Box<dyn Error>> {
+        let pointer = details["subaccount"]
+            .as_u64()
+            .ok_or("missing or invalid `subaccount`")?;
+        let pointer =
+            u16::try_from(pointer).map_err(|_| format!("invalid subaccount {}", pointer))?;
+        Ok(self
+            .get_wallet()?
+            .get_subaccount(pointer)
+            .ok_or_else(|| format!("unknown subaccount {}", pointer))?)
+    }
+
+    fn login(&mut self, details: Value) -> Result<Value, Box<dyn Error>> {
+        let mnemonic = details["mnemonic"].as_str().ok_or("missing mnemonic")?;
+        let mnemonic = Mnemonic::from_phrase(mnemonic, Language::English)?;
+        let seed = Seed::new(&mnemonic, details["password"].as_str().unwrap_or(""));
+        let xprv = ExtendedPrivKey::new_master(Network::Testnet, seed.as_bytes())?;
+        let signer: Arc<dyn UserSigner> = Arc::new(SoftwareSigner::new(xprv));
+
+        let mut config = GAClientConfig::new();
+        if let Some(endpoint) = &self.config.endpoint {
+            config = config.endpoint(endpoint);
+        }
+        if let Some(proxy) = &self.config.proxy {
+            config = config.proxy(proxy);
+        }
+        if let Some(path) = &self.config.device_id_file {
+            config = config.persisted_device_id(path)?;
+        }
+        if let Some(timeout) = self.config.timeout {
+            config = config.call_timeout(Duration::from_secs(timeout));
+        }
+
+        let electrum_url = self
+            .config
+            .electrum_url
+            .as_deref()
+            .unwrap_or("ssl://electrum.blockstream.info:60002");
+        let client = ElectrumClient::new(electrum_url, self.config.proxy.as_deref())?;
+        let client = Arc::new(ElectrumBlockchain::from(client));
+        let database = sled::open(
+            self.config
+                .database_path
+                .clone()
+                .unwrap_or_else(|| "neerg-db".into()),
+        )?;
+
+        let resolver = Arc::clone(&self.resolver) as Arc<dyn TwoFactorResolver>;
+        let wallet = self.runtime.block_on(async {
+            let session = Arc::new(GAClient::new(config, Arc::clone(&signer)).await?);
+            GAClient::spawn_supervisor(&session);
+
+            let twofactor_config = session.get_2fa_config().await?;
+            let pointers = std::iter::once(0)
+                .chain(session.get_subaccounts().iter().map(|s| s.pointer))
+                .collect::<Vec<_>>();
+
+            let mut wallet = GreenWallet::new(Arc::clone(&session));
+            for pointer in pointers {
+                let namespace = get_namespace(Network::Testnet, signer.get_fingerprint()?, pointer);
+                let subaccount = Subaccount::new(
+                    &signer,
+                    &session.get_gait_path(),
+                    pointer,
+                    database.open_tree(&namespace)?,
+                    &client,
+                    &session,
+                    Arc::clone(&resolver),
+                    twofactor_config.clone(),
+                )?
+                .with_local_store(database.open_tree(get_local_namespace(&namespace))?.into());
+                subaccount.sync(noop_progress(), None)?;
+                subaccount.refresh_utxo_status().await?;
+
+                wallet.add_subaccount(pointer, subaccount);
+            }
+
+            Ok::<_, Box<dyn Error>>(wallet)
+        })?;
+        self.wallet = Some(wallet);
+
+        Ok(Value::Null)
+    }
+
+    fn get_subaccounts(&self) -> Result<Value, Box<dyn Error>> {
+        Ok(serde_json::to_value(
+            self.get_wallet()?.list_subaccounts(true)?,
+        )?)
+    }
+
+    fn get_receive_address(&self, details: Value) -> Result<Value, Box<dyn Error>> {
+        let subaccount = self.get_subaccount(&details)?;
+
+        // The address validator talks to the server from inside the runtime
+        let address = self.runtime.enter(|| subaccount.get_new_address())?;
+
+        Ok(json!({ "address": address.to_string() }))
+    }
+
+    fn create_transaction(&self, details: Value) -> Result<Value, Box<dyn Error>> {
+        let session = self.get_wallet()?.get_session();
+        let subaccount = self.get_subaccount(&details)?;
+
+        // The amount is ignored when sending everything
+        let send_all = details["send_all"].as_bool().unwrap_or(false);
+
+        let mut recipients = Vec::new();
+        for addressee in details["addressees"]
+            .as_array()
+            .ok_or("missing addressees")?
+        {
+            let address = addressee["address"]
+                .as_str()
+                .ok_or("missing or invalid `address` in the addressees")?;
+            let address = Address::from_str(address)?;
+            if address.network != Network::Testnet {
+                return Err("the recipient address is for the wrong network".into());
+            }
+            let satoshi = addressee["satoshi"]
+                .as_u64()
+                .ok_or("missing or invalid `satoshi` in the addressees")?;
+
+            let script_pubkey = address.script_pubkey();
+            let dust_limit = get_dust_limit(&script_pubkey);
+            if !send_all && satoshi < dust_limit {
+                return Err(format!(
+                    "the amount for {} is below the dust limit of {} sat",
+                    address, dust_limit
+                )
+                .into());
+            }
+            recipients.push((script_pubkey, satoshi));
+        }
+
+        let min_fee_rate = session.get_min_fee_rate();
+        let fee_rate = match (details["fee_rate"].as_f64(), details["fee_target"].as_str()) {
+            (Some(fee_rate), _) if (fee_rate as f32) < min_fee_rate.as_sat_vb() => {
+                return Err("the fee rate is below the server minimum".into())
+            }
+            (Some(fee_rate), _) => FeeRate::from_sat_per_vb(fee_rate as f32),
+            (None, Some(target)) => session.get_fee_rate(FeeTarget::from_str(target)?),
+            (None, None) => session.get_fee_rate(FeeTarget::Medium),
+        };
+
+        let coin_control = subaccount.get_coin_control(&[])?;
+        let mut builder = TxBuilder::with_recipients(recipients)
+            .unspendable(coin_control.unspendable)
+            .enable_rbf()
+            .fee_rate(fee_rate);
+        if send_all {
+            builder = builder.send_all();
+        }
+        // The change address is checked with the server like the receive ones
+        let (psbt, details) = self.runtime.enter(|| subaccount.create_tx(builder))?;
+
+        Ok(json!({
+            "psbt": psbt_to_base64(&psbt),
+            "fee": details.fees,
+            "sent": details.sent,
+            "received": details.received,
+        }))
+    }
+
+    fn sign_transaction(&self, details: Value) -> Result<Value, Box<dyn Error>> {
+        let psbt = psbt_from_base64(details["psbt"].as_str().ok_or("missing psbt")?)?;
+        let subaccount = self.get_subaccount(&details)?;
+
+        // The service signer needs the runtime to reach the server
+        let (psbt, finalized) = self.runtime.enter(|| subaccount.sign(psbt, None))?;
+
+        Ok(json!({ "psbt": psbt_to_base64(&psbt), "finalized": finalized }))
+    }
+
+    fn send_transaction(&self, details: Value) -> Result<Value, Box<dyn Error>> {
+        let psbt = psbt_from_base64(details["psbt"].as_str().ok_or("missing psbt")?)?;
+        let subaccount = self.get_subaccount(&details)?;
+
+        let txid = subaccount.broadcast(psbt.extract_tx())?;
+
+        Ok(json!({ "txid": txid }))
+    }
+}
+
+fn set_last_error(error: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+}
+
+fn parse_json(input: *const c_char) -> Result<Value, Box<dyn Error>> {
+    if input.is_null() {
+        return Ok(Value::Null);
+    }
+    let input = unsafe { CStr::from_ptr(input) };
+
+    Ok(serde_json::from_str(input.to_str()?)?)
+}
+
+unsafe fn write_output(output: *mut *mut c_char, value: Value) -> Result<(), Box<dyn Error>> {
+    if !output.is_null() {
+        *output = CString::new(value.to_string())?.into_raw();
+    }
+
+    Ok(())
+}
+
+/// Run `f`, storing its error or panic as the thread error
+fn wrap<F>(output: *mut *mut c_char, f: F) -> c_int
+where
+    F: FnOnce() -> Result<Value, Box<dyn Error>>,
+{
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let value = f()?;
+        unsafe { write_output(output, value) }
+    }));
+
+    match result {
+        Ok(Ok(())) => GA_OK,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            GA_ERROR
+        }
+        Err(_) => {
+            set_last_error("internal error".to_string());
+            GA_ERROR
+        }
+    }
+}
+
+unsafe fn get_session<'a>(session: *mut GA_session) -> Result<&'a mut GA_session, Box<dyn Error>> {
+    Ok(session.as_mut().ok_or("null session")?)
+}
+
+/// Create a session from the JSON `config`, which can be `NULL` for the defaults
+///
+/// Recognized keys: `endpoint`, `proxy`, `electrum_url`, `database_path`, `device_id_file` and
+/// `timeout`.
+#[no_mangle]
+pub unsafe extern "C" fn GA_create_session(
+    config: *const c_char,
+    session: *mut *mut GA_session,
+) -> c_int {
+    wrap(ptr::null_mut(), || {
+        if session.is_null() {
+            return Err("null session".into());
+        }
+        let config = match parse_json(config)? {
+            Value::Null => SessionConfig::default(),
+            config => serde_json::from_value(config)?,
+        };
+        let runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()?;
+
+        *session = Box::into_raw(Box::new(GA_session {
+            runtime,
+            config,
+            resolver: Arc::new(CallbackResolver::default()),
+            wallet: None,
+        }));
+
+        Ok(Value::Null)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn GA_destroy_session(session: *mut GA_session) -> c_int {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+    GA_OK
+}
+
+/// Register the callback used when the server asks for a 2FA code, see
+/// [`GA_twofactor_callback`]
+#[no_mangle]
+pub unsafe extern "C" fn GA_set_twofactor_callback(
+    session: *mut GA_session,
+    callback: Option<GA_twofactor_callback>,
+    context: *mut c_void,
+) -> c_int {
+    wrap(ptr::null_mut(), || {
+        let session = get_session(session)?;
+        *session.resolver.callback.lock().unwrap() = callback.map(|function| Callback {
+            function,
+            context: context as usize,
+        });
+
+        Ok(Value::Null)
+    })
+}
+
+/// Login with `{"mnemonic": ..., "password": ...}`, opening and syncing every subaccount
+#[no_mangle]
+pub unsafe extern "C" fn GA_login(session: *mut GA_session, details: *const c_char) -> c_int {
+    wrap(ptr::null_mut(), || {
+        get_session(session)?.login(parse_json(details)?)
+    })
+}
+
+/// List the subaccounts as `[{"pointer": ..., "name": ..., "hidden": ..., "balance": ...}]`,
+/// including the archived ones
+#[no_mangle]
+pub unsafe extern "C" fn GA_get_subaccounts(
+    session: *mut GA_session,
+    output: *mut *mut c_char,
+) -> c_int {
+    wrap(output, || get_session(session)?.get_subaccounts())
+}
+
+/// New address of `{"subaccount": ...}`, returned as `{"address": ...}`
+#[no_mangle]
+pub unsafe extern "C" fn GA_get_receive_address(
+    session: *mut GA_session,
+    details: *const c_char,
+    output: *mut *mut c_char,
+) -> c_int {
+    wrap(output, || {
+        get_session(session)?.get_receive_address(parse_json(details)?)
+    })
+}
+
+/// Create an unsigned transaction from `{"subaccount": ..., "addressees": [{"address": ...,
+/// "satoshi": ...}], "send_all": ..., "fee_rate": ..., "fee_target": ...}`
+///
+/// Returns `{"psbt": ..., "fee": ..., "sent": ..., "received": ...}` with the PSBT in base64.
+#[no_mangle]
+pub unsafe extern "C" fn GA_create_transaction(
+    session: *mut GA_session,
+    details: *const c_char,
+    output: *mut *mut c_char,
+) -> c_int {
+    wrap(output, || {
+        get_session(session)?.create_transaction(parse_json(details)?)
+    })
+}
+
+/// Sign `{"subaccount": ..., "psbt": ...}` with the user key and the service, which can call the
+/// 2FA callback. Returns `{"psbt": ..., "finalized": ...}`.
+#[no_mangle]
+pub unsafe extern "C" fn GA_sign_transaction(
+    session: *mut GA_session,
+    details: *const c_char,
+    output: *mut *mut c_char,
+) -> c_int {
+    wrap(output, || {
+        get_session(session)?.sign_transaction(parse_json(details)?)
+    })
+}
+
+/// Broadcast the finalized `{"subaccount": ..., "psbt": ...}`, returning `{"txid": ...}`
+#[no_mangle]
+pub unsafe extern "C" fn GA_send_transaction(
+    session: *mut GA_session,
+    details: *const c_char,
+    output: *mut *mut c_char,
+) -> c_int {
+    wrap(output, || {
+        get_session(session)?.send_transaction(parse_json(details)?)
+    })
+}
+
+/// Details of the last error of the calling thread as `{"details": ...}`
+#[no_mangle]
+pub unsafe extern "C" fn GA_get_thread_error_details(output: *mut *mut c_char) -> c_int {
+    let details = LAST_ERROR
+        .with(|last| last.borrow().clone())
+        .unwrap_or_default();
+    match write_output(output, json!({ "details": details })) {
+        Ok(()) => GA_OK,
+        Err(_) => GA_ERROR,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn GA_destroy_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
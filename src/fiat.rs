@@ -0,0 +1,42 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Satoshi in one bitcoin
+const SATOSHI_PER_BTC: f64 = 100_000_000.0;
+
+/// Price of a bitcoin in `currency` according to `exchange`, the pricing source of the account
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FiatRate {
+    pub currency: String,
+    pub exchange: String,
+    /// Fiat per BTC
+    pub rate: f64,
+}
+
+impl FiatRate {
+    /// Value of `satoshi` in the fiat currency, negative amounts are kept negative
+    pub fn to_fiat(&self, satoshi: i64) -> FiatAmount {
+        FiatAmount {
+            amount: satoshi as f64 / SATOSHI_PER_BTC * self.rate,
+            currency: self.currency.clone(),
+        }
+    }
+
+    /// Satoshi worth `amount` in the fiat currency, rounded down
+    pub fn to_satoshi(&self, amount: f64) -> u64 {
+        (amount / self.rate * SATOSHI_PER_BTC).floor() as u64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FiatAmount {
+    pub amount: f64,
+    pub currency: String,
+}
+
+impl fmt::Display for FiatAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} {}", self.amount, self.currency)
+    }
+}
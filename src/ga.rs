@@ -1,96 +1,444 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc};
 
+use serde::Serialize;
+
+use tokio::stream::{Stream, StreamExt};
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
+use tokio::time::{delay_for, timeout};
+
 use wamp_async::{Arg, Client};
 
 use bdk::bitcoin;
 use bdk::descriptor::HDKeyPaths;
 use bdk::wallet::address_validator::{AddressValidator, AddressValidatorError};
 use bdk::wallet::signer::{Signer, SignerError};
-use bdk::ScriptType;
+use bdk::{FeeRate, ScriptType};
 
 use bitcoin::blockdata::script::Builder;
-use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::consensus::encode::{deserialize, serialize, serialize_hex, VarInt};
 use bitcoin::hashes::hex::{FromHex, ToHex};
-use bitcoin::hashes::{sha256d, Hash};
-use bitcoin::secp256k1::{Message, Secp256k1};
-use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey, Fingerprint};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256d, sha512, Hash, HashEngine};
+use bitcoin::secp256k1::Message;
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, Fingerprint};
 use bitcoin::util::psbt;
-use bitcoin::{Address, Network, Script, Transaction};
+use bitcoin::{Address, Script, Transaction, Txid};
 
+use crate::config::GAClientConfig;
+use crate::fees::{fee_rate_from_sat_per_kvb, select_fee_rate, FeeTarget};
+use crate::fiat::FiatRate;
+use crate::proxy::spawn_websocket_forwarder;
+use crate::signer::UserSigner;
 use crate::twofactor::*;
 use crate::types::*;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    Reconnecting { attempt: u32 },
+}
+
+/// Tracks the state of the current connection, ignoring the events of the ones it replaced
+#[derive(Debug)]
+struct ConnectionMonitor {
+    state_sender: watch::Sender<ConnectionState>,
+    generation: AtomicUsize,
+    /// Url to connect to, the local forwarder when using a proxy. It's shared by the
+    /// reconnections, so that only one forwarder is started
+    endpoint: String,
+}
+
+impl ConnectionMonitor {
+    fn set_state(&self, state: ConnectionState) {
+        let _ = self.state_sender.broadcast(state);
+    }
+}
+
 pub struct GAClient {
-    session: Client,
-    auth_response: AuthenticateResponse,
+    session: RwLock<Client>,
+    config: GAClientConfig,
+    signer: Arc<dyn UserSigner>,
+    auth_response: std::sync::RwLock<AuthenticateResponse>,
+
+    monitor: Arc<ConnectionMonitor>,
+    state_receiver: watch::Receiver<ConnectionState>,
+    reconnect_lock: Mutex<()>,
+
+    events: broadcast::Sender<GreenEvent>,
+    fees: Arc<std::sync::RwLock<ServerFees>>,
+    settings: std::sync::RwLock<Settings>,
+    fiat_rate: std::sync::RwLock<Option<f64>>,
+    subaccounts: std::sync::RwLock<Vec<AuthenticateSubaccount>>,
+}
+
+/// Units accepted in the appearance, the server doesn't check them but other clients do
+const UNITS: [&str; 5] = ["BTC", "mBTC", "\u{00B5}BTC", "bits", "sats"];
+
+/// Latest fee estimates sent by the server, either when logging in or with a notification
+#[derive(Debug)]
+struct ServerFees {
+    estimates: HashMap<String, FeeEstimate>,
+    min_fee: u64,
 }
 
 impl GAClient {
-    pub async fn new(xprv: &ExtendedPrivKey) -> Result<Self, Box<dyn Error>> {
-        let (mut client, (evt_loop, _)) =
-            Client::connect("wss://testwss.greenaddress.it/v2/ws/", None).await?;
+    pub async fn new(
+        config: GAClientConfig,
+        signer: Arc<dyn UserSigner>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (monitor, state_receiver) = Self::new_monitor(&config).await?;
+        let client = Self::connect(&config, &monitor).await?;
 
-        tokio::spawn(evt_loop);
-        client.join_realm("realm1").await?;
+        Self::login(client, config, signer, monitor, state_receiver).await
+    }
 
-        let secp = Secp256k1::signing_only();
+    pub async fn register(
+        config: GAClientConfig,
+        signer: Arc<dyn UserSigner>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (monitor, state_receiver) = Self::new_monitor(&config).await?;
+        let client = Self::connect(&config, &monitor).await?;
 
-        let master_pk = xprv.private_key.public_key(&secp);
-        let master_address = Address::p2pkh(&master_pk, Network::Testnet);
+        let master_xpub = signer.get_xpub(&vec![].into())?;
+        let gait_path = get_gait_path_bytes(signer.as_ref())?;
 
-        let (challenge, _) = client
-            .call(
+        timeout(
+            config.call_timeout,
+            client.call(
+                "com.greenaddress.login.register",
+                Some(vec![
+                    Arg::String(master_xpub.public_key.to_string()),
+                    Arg::String(master_xpub.chain_code.to_hex()),
+                    Arg::String(config.user_agent.clone()),
+                    Arg::String(gait_path.to_hex()),
+                ]),
+                None,
+            ),
+        )
+        .await??;
+
+        Self::login(client, config, signer, monitor, state_receiver).await
+    }
+
+    async fn new_monitor(
+        config: &GAClientConfig,
+    ) -> Result<(Arc<ConnectionMonitor>, watch::Receiver<ConnectionState>), Box<dyn Error>> {
+        let endpoint = match &config.proxy {
+            Some(proxy) => spawn_websocket_forwarder(proxy, &config.endpoint)
+                .await
+                .map_err(|e| e.to_string())?,
+            None => config.endpoint.clone(),
+        };
+
+        let (state_sender, state_receiver) = watch::channel(ConnectionState::Disconnected);
+        let monitor = ConnectionMonitor {
+            state_sender,
+            generation: AtomicUsize::new(0),
+            endpoint,
+        };
+
+        Ok((Arc::new(monitor), state_receiver))
+    }
+
+    async fn connect(
+        config: &GAClientConfig,
+        monitor: &Arc<ConnectionMonitor>,
+    ) -> Result<Client, Box<dyn Error>> {
+        let (mut client, (evt_loop, _)) = timeout(
+            config.connect_timeout,
+            Client::connect(&monitor.endpoint, None),
+        )
+        .await??;
+
+        // The event loop only returns when the connection drops
+        let generation = monitor.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let monitor = Arc::clone(monitor);
+        tokio::spawn(async move {
+            if let Err(e) = evt_loop.await {
+                log::warn!("connection to the Green server lost: {:?}", e);
+            }
+            if monitor.generation.load(Ordering::SeqCst) == generation {
+                monitor.set_state(ConnectionState::Disconnected);
+            }
+        });
+        timeout(config.connect_timeout, client.join_realm(&config.realm)).await??;
+
+        Ok(client)
+    }
+
+    async fn login(
+        client: Client,
+        config: GAClientConfig,
+        signer: Arc<dyn UserSigner>,
+        monitor: Arc<ConnectionMonitor>,
+        state_receiver: watch::Receiver<ConnectionState>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let auth_response = Self::authenticate(&client, &config, signer.as_ref()).await?;
+        monitor.set_state(ConnectionState::Connected);
+
+        let (events, _) = broadcast::channel(64);
+        Self::subscribe_notifications(&client, &auth_response, &events).await?;
+
+        let fees = Arc::new(std::sync::RwLock::new(ServerFees {
+            estimates: auth_response.fee_estimates.clone(),
+            min_fee: auth_response.min_fee,
+        }));
+        Self::spawn_fee_updater(&fees, events.subscribe());
+        let settings = std::sync::RwLock::new(Settings::from(&auth_response));
+        let fiat_rate = std::sync::RwLock::new(auth_response.fiat_exchange);
+        let subaccounts = std::sync::RwLock::new(auth_response.subaccounts.clone());
+
+        Ok(GAClient {
+            session: RwLock::new(client),
+            config,
+            signer,
+            auth_response: std::sync::RwLock::new(auth_response),
+            monitor,
+            state_receiver,
+            reconnect_lock: Mutex::new(()),
+            events,
+            fees,
+            settings,
+            fiat_rate,
+            subaccounts,
+        })
+    }
+
+    fn spawn_fee_updater(
+        fees: &Arc<std::sync::RwLock<ServerFees>>,
+        mut events: broadcast::Receiver<GreenEvent>,
+    ) {
+        let fees = Arc::downgrade(fees);
+        tokio::spawn(async move {
+            loop {
+                let estimates = match events.recv().await {
+                    Ok(GreenEvent::FeeEstimates(estimates)) => estimates,
+                    Ok(_) | Err(broadcast::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::RecvError::Closed) => break,
+                };
+                match fees.upgrade() {
+                    Some(fees) => fees.write().unwrap().estimates = estimates,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    async fn authenticate(
+        client: &Client,
+        config: &GAClientConfig,
+        signer: &dyn UserSigner,
+    ) -> Result<AuthenticateResponse, Box<dyn Error>> {
+        let master_xpub = signer.get_xpub(&vec![].into())?;
+        let master_address = Address::p2pkh(&master_xpub.public_key, master_xpub.network);
+
+        let (challenge, _) = timeout(
+            config.call_timeout,
+            client.call(
                 "com.greenaddress.login.get_trezor_challenge",
                 Some(vec![
                     Arg::String(master_address.to_string()),
                     Arg::Bool(true),
                 ]),
                 None,
-            )
-            .await?;
+            ),
+        )
+        .await??;
 
-        let challenge = match &challenge.as_ref().unwrap()[0] {
-            Arg::Uri(challenge) => {
-                get_sign_message_hash(&("greenaddress.it      login ".to_owned() + challenge))
-            }
-            _ => unimplemented!(),
+        let challenge = match challenge.as_ref().and_then(|args| args.get(0)) {
+            Some(Arg::Uri(challenge)) => "greenaddress.it      login ".to_owned() + challenge,
+            _ => return Err("invalid login challenge from the Green server".into()),
         };
 
-        let sign_key = xprv
-            .derive_priv(&secp, &[ChildNumber::from_normal_idx(0x4741b11e)?])?
-            .private_key
-            .key;
-        let signature = secp.sign(&challenge, &sign_key).serialize_der();
+        let signature = signer
+            .sign_message(&get_login_path(), &challenge)?
+            .serialize_der();
 
-        let (auth_response, _) = client
-            .call(
+        let (auth_response, _) = timeout(
+            config.call_timeout,
+            client.call(
                 "com.greenaddress.login.authenticate",
                 Some(vec![
                     Arg::String(signature.to_hex()),
                     Arg::Bool(false),
                     Arg::String("GA".into()),
-                    Arg::String("my-device-id".into()),
-                    Arg::String("[v2,sw]neerg".into()),
+                    Arg::String(config.device_id.clone()),
+                    Arg::String(config.user_agent.clone()),
                 ]),
                 None,
-            )
-            .await?;
+            ),
+        )
+        .await??;
 
         let mut auth_response = serde_json::to_value(&auth_response)?;
-        let auth_response: AuthenticateResponse = serde_json::from_value(auth_response[0].take())?;
+        Ok(serde_json::from_value(auth_response[0].take())?)
+    }
 
-        Ok(GAClient {
-            session: client,
-            auth_response,
-        })
+    /// Current state of the connection to the Green server
+    pub fn get_connection_state(&self) -> ConnectionState {
+        *self.state_receiver.borrow()
+    }
+
+    /// Stream of the changes of the connection state
+    pub fn subscribe_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_receiver.clone()
+    }
+
+    /// Reconnect and authenticate again, retrying with an exponential backoff
+    async fn reconnect(&self) -> Result<(), Box<dyn Error>> {
+        let _guard = self.reconnect_lock.lock().await;
+        // Somebody else reconnected while we were waiting for the lock
+        if self.get_connection_state() == ConnectionState::Connected {
+            return Ok(());
+        }
+
+        let mut backoff = self.config.reconnect_backoff;
+        for attempt in 1..=self.config.max_reconnect_attempts {
+            self.monitor
+                .set_state(ConnectionState::Reconnecting { attempt });
+
+            match self.replace_session().await {
+                Ok(()) => {
+                    self.monitor.set_state(ConnectionState::Connected);
+                    return Ok(());
+                }
+                Err(e) => log::warn!("reconnection attempt {} failed: {:?}", attempt, e),
+            }
+
+            delay_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, self.config.max_reconnect_backoff);
+        }
+
+        self.monitor.set_state(ConnectionState::Disconnected);
+        Err("unable to reconnect to the Green server".into())
     }
 
+    async fn replace_session(&self) -> Result<(), Box<dyn Error>> {
+        let client = Self::connect(&self.config, &self.monitor).await?;
+        let auth_response = Self::authenticate(&client, &self.config, self.signer.as_ref()).await?;
+        Self::subscribe_notifications(&client, &auth_response, &self.events).await?;
+
+        // Notifications may have been missed while disconnected, and another client may have
+        // changed the settings
+        *self.settings.write().unwrap() = Settings::from(&auth_response);
+        *self.fiat_rate.write().unwrap() = auth_response.fiat_exchange;
+        *self.subaccounts.write().unwrap() = auth_response.subaccounts.clone();
+        *self.fees.write().unwrap() = ServerFees {
+            estimates: auth_response.fee_estimates.clone(),
+            min_fee: auth_response.min_fee,
+        };
+        *self.auth_response.write().unwrap() = auth_response;
+
+        *self.session.write().await = client;
+        Ok(())
+    }
+
+    async fn subscribe_notifications(
+        client: &Client,
+        auth_response: &AuthenticateResponse,
+        events: &broadcast::Sender<GreenEvent>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (_, blocks) = client.subscribe("com.greenaddress.blocks").await?;
+        forward_notifications(blocks, events.clone(), |value| {
+            Ok(GreenEvent::Block(serde_json::from_value(value)?))
+        });
+
+        let (_, transactions) = client
+            .subscribe(&format!(
+                "com.greenaddress.txs.wallet_{}",
+                auth_response.receiving_id
+            ))
+            .await?;
+        forward_notifications(transactions, events.clone(), |value| {
+            Ok(GreenEvent::Transaction(serde_json::from_value(value)?))
+        });
+
+        let (_, fee_estimates) = client.subscribe("com.greenaddress.fee_estimates").await?;
+        forward_notifications(fee_estimates, events.clone(), |value| {
+            Ok(GreenEvent::FeeEstimates(serde_json::from_value(value)?))
+        });
+
+        Ok(())
+    }
+
+    /// Stream of the notifications sent by the server, kept across reconnections
+    pub fn subscribe_events(&self) -> broadcast::Receiver<GreenEvent> {
+        self.events.subscribe()
+    }
+
+    /// Keep the connection alive in the background, reconnecting as soon as it drops instead of
+    /// waiting for the next call
+    pub fn spawn_supervisor(client: &Arc<GAClient>) {
+        let mut state_receiver = client.subscribe_connection_state();
+        let client = Arc::downgrade(client);
+
+        tokio::spawn(async move {
+            while let Some(state) = state_receiver.recv().await {
+                let client = match client.upgrade() {
+                    Some(client) => client,
+                    None => break,
+                };
+
+                // Give up once the attempts run out, the next call tries again
+                if state == ConnectionState::Disconnected {
+                    if let Err(e) = client.reconnect().await {
+                        log::error!("{}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Call an idempotent method, transparently reconnecting and retrying if the connection drops
     async fn call(&self, name: &str, args: Vec<Arg>) -> Result<serde_json::Value, Box<dyn Error>> {
-        let (response, _) = self.session.call(name, Some(args), None).await?;
+        let mut attempt = 0;
+        loop {
+            match self.call_once(name, args.clone()).await {
+                Err(e)
+                    if attempt < self.config.max_reconnect_attempts
+                        && self.get_connection_state() != ConnectionState::Connected =>
+                {
+                    log::debug!("retrying `{}` after a disconnection: {:?}", name, e)
+                }
+                result => return result,
+            }
+
+            attempt += 1;
+            self.reconnect().await?;
+        }
+    }
+
+    /// Call a method once, for calls that can't be safely repeated
+    async fn call_once(
+        &self,
+        name: &str,
+        args: Vec<Arg>,
+    ) -> Result<serde_json::Value, Box<dyn Error>> {
+        if self.get_connection_state() != ConnectionState::Connected {
+            self.reconnect().await?;
+        }
 
-        Ok(serde_json::to_value(&response)?)
+        let session = self.session.read().await;
+        let response = timeout(
+            self.config.call_timeout,
+            session.call(name, Some(args), None),
+        )
+        .await;
+
+        match response {
+            Ok(response) => Ok(serde_json::to_value(&response?.0)?),
+            Err(e) => {
+                // Consider the connection dead when the server stops answering
+                self.monitor.set_state(ConnectionState::Disconnected);
+                Err(e.into())
+            }
+        }
     }
 
     pub async fn vault_fund(&self, subaccount: u16) -> Result<VaultFundResponse, Box<dyn Error>> {
@@ -116,7 +464,7 @@ impl GAClient {
         let twofactor_data = serde_json::from_value(twofactor_data)?;
 
         let mut response = self
-            .call(
+            .call_once(
                 "com.greenaddress.vault.sign_raw_tx",
                 vec![Arg::String(raw_tx), twofactor_data],
             )
@@ -124,6 +472,86 @@ impl GAClient {
         Ok(serde_json::from_value(response[0].take())?)
     }
 
+    pub async fn get_transactions(
+        &self,
+        subaccount: u16,
+        page_id: u32,
+    ) -> Result<TransactionListResponse, Box<dyn Error>> {
+        let mut response = self
+            .call(
+                "com.greenaddress.txs.get_list_v2",
+                vec![
+                    Arg::Integer(page_id as usize),
+                    Arg::String("".into()),
+                    Arg::String("".into()),
+                    Arg::String("".into()),
+                    Arg::Integer(subaccount as usize),
+                ],
+            )
+            .await?;
+        Ok(serde_json::from_value(response[0].take())?)
+    }
+
+    /// Fetch every page of the server's transaction list
+    pub async fn get_all_transactions(
+        &self,
+        subaccount: u16,
+    ) -> Result<Vec<ServerTransaction>, Box<dyn Error>> {
+        let mut transactions = Vec::new();
+        let mut page_id = 0;
+        loop {
+            let page = self.get_transactions(subaccount, page_id).await?;
+            transactions.extend(page.list);
+
+            match page.next_page_id {
+                Some(next) => page_id = next,
+                None => break Ok(transactions),
+            }
+        }
+    }
+
+    pub async fn set_memo(&self, txid: &Txid, memo: &str) -> Result<(), Box<dyn Error>> {
+        self.call(
+            "com.greenaddress.txs.change_memo",
+            vec![Arg::String(txid.to_string()), Arg::String(memo.into())],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_unspent_outputs(
+        &self,
+        subaccount: u16,
+    ) -> Result<Vec<ServerUnspentOutput>, Box<dyn Error>> {
+        let mut response = self
+            .call(
+                "com.greenaddress.txs.get_all_unspent_outputs",
+                vec![Arg::Integer(0), Arg::Integer(subaccount as usize)],
+            )
+            .await?;
+        Ok(serde_json::from_value(response[0].take())?)
+    }
+
+    /// Change the status of unspent outputs on the server, only `Default` and `Frozen` are valid
+    pub async fn set_unspent_outputs_status(
+        &self,
+        changes: Vec<UtxoStatusChange>,
+        twofactor_data: Option<TwoFactorData>,
+    ) -> Result<(), Box<dyn Error>> {
+        if changes.iter().any(|c| c.user_status == UtxoStatus::Dust) {
+            return Err("the dust status can't be stored on the server".into());
+        }
+
+        let changes = serde_json::from_value(serde_json::to_value(&changes)?)?;
+
+        self.call_once(
+            "com.greenaddress.vault.set_utxo_status",
+            vec![changes, twofactor_arg(twofactor_data)?],
+        )
+        .await?;
+        Ok(())
+    }
+
     pub async fn get_2fa_config(&self) -> Result<TwoFactorConfigResponse, Box<dyn Error>> {
         let mut response = self
             .call("com.greenaddress.twofactor.get_config", vec![])
@@ -134,48 +562,350 @@ impl GAClient {
     pub async fn request_2fa_code(
         &self,
         method: TwoFactorMethod,
-        action: &str,
+        action: TwoFactorAction,
     ) -> Result<(), Box<dyn Error>> {
         if method == TwoFactorMethod::Gauth {
             return Ok(());
         }
 
-        self.call(
+        let mut args = vec![Arg::String(action.get_name().into())];
+        if let Some(data) = action.get_data() {
+            args.push(serde_json::from_value(data)?);
+        }
+
+        self.call_once(
             &format!("com.greenaddress.twofactor.request_{}", method.to_string()),
-            vec![Arg::String(action.into())],
+            args,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Ask `resolver` for a code authorizing `action`, `None` when 2FA is not enabled
+    pub async fn resolve_2fa_data(
+        &self,
+        action: TwoFactorAction,
+        resolver: &dyn TwoFactorResolver,
+    ) -> Result<Option<TwoFactorData>, Box<dyn Error>> {
+        let twofactor_config = self.get_2fa_config().await?;
+        if !twofactor_config.any {
+            return Ok(None);
+        }
+
+        let method = resolver.get_method(twofactor_config.get_enabled());
+        if resolver.needs_request() {
+            self.request_2fa_code(method, action).await?;
+        }
+
+        Ok(Some(TwoFactorData {
+            code: resolver.get_code(),
+            method,
+            bump_fee_amount: None,
+        }))
+    }
+
+    /// Settings as of the last login or change
+    pub fn get_settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Values accepted by the server for the CSV time
+    pub fn get_csv_times(&self) -> Vec<u32> {
+        self.auth_response.read().unwrap().csv_times.clone()
+    }
+
+    /// Apply the differences between `settings` and the current ones
+    ///
+    /// Changing the nLockTime or the CSV time needs a 2FA code, which is asked to `resolver` for
+    /// each of them. The settings changed before an error are kept.
+    pub async fn change_settings(
+        &self,
+        settings: &Settings,
+        resolver: &dyn TwoFactorResolver,
+    ) -> Result<(), Box<dyn Error>> {
+        let current = self.get_settings();
+
+        if settings.appearance != current.appearance {
+            self.set_appearance(&settings.appearance).await?;
+        }
+        if settings.pricing != current.pricing {
+            self.set_pricing_source(&settings.pricing).await?;
+        }
+        if settings.nlocktime != current.nlocktime {
+            let action = TwoFactorAction::SetNlocktime {
+                value: settings.nlocktime,
+            };
+            let twofactor_data = self.resolve_2fa_data(action, resolver).await?;
+            self.set_nlocktime(settings.nlocktime, twofactor_data)
+                .await?;
+        }
+        if settings.csvtime != current.csvtime {
+            let action = TwoFactorAction::SetCsvtime {
+                value: settings.csvtime,
+            };
+            let twofactor_data = self.resolve_2fa_data(action, resolver).await?;
+            self.set_csvtime(settings.csvtime, twofactor_data).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_appearance(&self, appearance: &Appearance) -> Result<(), Box<dyn Error>> {
+        if !UNITS.contains(&appearance.unit.as_str()) {
+            return Err(format!(
+                "unknown unit `{}`, expected one of {:?}",
+                appearance.unit, UNITS
+            )
+            .into());
+        }
+        if appearance.required_num_blocks == 0 {
+            return Err("the fee confirmation target must be at least one block".into());
+        }
+        if let Some(custom_fee_rate) = appearance.custom_fee_rate {
+            if custom_fee_rate < self.fees.read().unwrap().min_fee {
+                return Err("the custom fee rate is below the server minimum".into());
+            }
+        }
+
+        self.call(
+            "com.greenaddress.login.set_appearance",
+            vec![serde_json::from_value(serde_json::to_value(appearance)?)?],
+        )
+        .await?;
+
+        self.settings.write().unwrap().appearance = appearance.clone();
+        Ok(())
+    }
+
+    /// Change the currency and the exchange of the fiat rate, refreshing the rate
+    pub async fn set_pricing_source(&self, pricing: &PricingSource) -> Result<(), Box<dyn Error>> {
+        let currencies = self.get_available_currencies().await?;
+        let offered = currencies
+            .per_exchange
+            .get(&pricing.exchange)
+            .ok_or_else(|| format!("unknown exchange `{}`", pricing.exchange))?;
+        if !offered.contains(&pricing.currency) {
+            return Err(format!(
+                "{} is not priced by {}, expected one of {:?}",
+                pricing.currency, pricing.exchange, offered
+            )
+            .into());
+        }
+
+        self.call(
+            "com.greenaddress.login.set_pricing_source",
+            vec![
+                Arg::String(pricing.currency.clone()),
+                Arg::String(pricing.exchange.clone()),
+            ],
+        )
+        .await?;
+
+        self.settings.write().unwrap().pricing = pricing.clone();
+        self.refresh_fiat_rate().await?;
+        Ok(())
+    }
+
+    /// Currencies the server can price, with the exchanges they come from
+    pub async fn get_available_currencies(&self) -> Result<AvailableCurrencies, Box<dyn Error>> {
+        let mut response = self
+            .call("com.greenaddress.login.available_currencies", vec![])
+            .await?;
+        Ok(serde_json::from_value(response[0].take())?)
+    }
+
+    /// Rate of the pricing source as of the last login or refresh, `None` when the account has
+    /// no pricing source or the exchange doesn't have a rate
+    pub fn get_fiat_rate(&self) -> Option<FiatRate> {
+        let pricing = self.settings.read().unwrap().pricing.clone();
+        if pricing.currency.is_empty() {
+            return None;
+        }
+
+        self.fiat_rate.read().unwrap().map(|rate| FiatRate {
+            currency: pricing.currency,
+            exchange: pricing.exchange,
+            rate,
+        })
+    }
+
+    /// Fetch the current rate of the pricing source
+    pub async fn refresh_fiat_rate(&self) -> Result<Option<FiatRate>, Box<dyn Error>> {
+        let pricing = self.get_settings().pricing;
+        let mut response = self
+            .call(
+                "com.greenaddress.login.get_fiat_rate",
+                vec![Arg::String(pricing.currency), Arg::String(pricing.exchange)],
+            )
+            .await?;
+        let response: FiatRateResponse = serde_json::from_value(response[0].take())?;
+
+        *self.fiat_rate.write().unwrap() = response.fiat_exchange;
+        Ok(self.get_fiat_rate())
+    }
+
+    /// Change the number of blocks after which the nLockTime recovery transactions are valid
+    pub async fn set_nlocktime(
+        &self,
+        value: u32,
+        twofactor_data: Option<TwoFactorData>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.call_once(
+            "com.greenaddress.login.set_nlocktime",
+            vec![Arg::Integer(value as usize), twofactor_arg(twofactor_data)?],
+        )
+        .await?;
+
+        self.settings.write().unwrap().nlocktime = value;
+        Ok(())
+    }
+
+    /// Change the number of blocks after which the CSV recovery path can be used
+    pub async fn set_csvtime(
+        &self,
+        value: u32,
+        twofactor_data: Option<TwoFactorData>,
+    ) -> Result<(), Box<dyn Error>> {
+        let csv_times = self.get_csv_times();
+        if !csv_times.is_empty() && !csv_times.contains(&value) {
+            return Err(format!("invalid CSV time, expected one of {:?}", csv_times).into());
+        }
+
+        self.call_once(
+            "com.greenaddress.login.set_csvtime",
+            vec![Arg::Integer(value as usize), twofactor_arg(twofactor_data)?],
+        )
+        .await?;
+
+        self.settings.write().unwrap().csvtime = value;
+        Ok(())
+    }
+
+    /// Ask the server to email the nLockTime recovery transactions
+    pub async fn send_nlocktimes(&self) -> Result<(), Box<dyn Error>> {
+        self.call("com.greenaddress.txs.send_nlocktime", vec![])
+            .await?;
+        Ok(())
+    }
+
+    pub fn get_gait_path(&self) -> Vec<u16> {
+        self.auth_response.read().unwrap().gait_path.clone()
+    }
+
+    /// Subaccounts created on the server, excluding the main account
+    pub fn get_subaccounts(&self) -> Vec<AuthenticateSubaccount> {
+        self.subaccounts.read().unwrap().clone()
+    }
+
+    pub async fn rename_subaccount(&self, pointer: u16, name: &str) -> Result<(), Box<dyn Error>> {
+        if pointer == 0 {
+            return Err("the main account can't be renamed".into());
+        }
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("the name can't be empty".into());
+        }
+
+        self.call(
+            "com.greenaddress.txs.rename_subaccount",
+            vec![Arg::Integer(pointer as usize), Arg::String(name.into())],
+        )
+        .await?;
+
+        self.update_subaccount(pointer, |s| s.name = name.to_string())
+    }
+
+    /// Archive or unarchive a subaccount, archived ones keep receiving funds
+    pub async fn set_subaccount_hidden(
+        &self,
+        pointer: u16,
+        hidden: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if pointer == 0 {
+            return Err("the main account can't be archived".into());
+        }
+
+        self.call(
+            "com.greenaddress.txs.set_subaccount_hidden",
+            vec![Arg::Integer(pointer as usize), Arg::Bool(hidden)],
         )
         .await?;
+
+        self.update_subaccount(pointer, |s| s.hidden = hidden)
+    }
+
+    fn update_subaccount<F>(&self, pointer: u16, update: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce(&mut AuthenticateSubaccount),
+    {
+        let mut subaccounts = self.subaccounts.write().unwrap();
+        let subaccount = subaccounts
+            .iter_mut()
+            .find(|s| s.pointer == pointer)
+            .ok_or_else(|| format!("unknown subaccount {}", pointer))?;
+        update(subaccount);
+
         Ok(())
     }
 
-    pub fn get_gait_path(&self) -> &Vec<u16> {
-        &self.auth_response.gait_path
+    pub fn get_earliest_key_creation_time(&self) -> u64 {
+        self.auth_response
+            .read()
+            .unwrap()
+            .earliest_key_creation_time
+    }
+
+    /// Fee rate to confirm within `target` according to the latest server estimates
+    pub fn get_fee_rate(&self, target: FeeTarget) -> FeeRate {
+        let fees = self.fees.read().unwrap();
+        select_fee_rate(&fees.estimates, fees.min_fee, target)
+    }
+
+    /// Lowest fee rate accepted by the server when signing
+    pub fn get_min_fee_rate(&self) -> FeeRate {
+        fee_rate_from_sat_per_kvb(self.fees.read().unwrap().min_fee)
     }
 }
 
 #[derive(Debug)]
-pub struct GASigner<R: TwoFactorResolver + 'static> {
+pub struct GASigner<R: TwoFactorResolver + ?Sized + 'static> {
     pub session: Arc<GAClient>,
     pub service_fingerprint: Fingerprint,
     pub resolver: Arc<R>,
     pub twofactor_config: TwoFactorConfigResponse,
 }
 
-impl<R: TwoFactorResolver> Signer for GASigner<R> {
-    fn sign_whole_tx(&self) -> bool {
-        true
+// Deriving it would require `R: Clone`, but only the `Arc` is cloned
+impl<R: TwoFactorResolver + ?Sized> Clone for GASigner<R> {
+    fn clone(&self) -> Self {
+        GASigner {
+            session: Arc::clone(&self.session),
+            service_fingerprint: self.service_fingerprint,
+            resolver: Arc::clone(&self.resolver),
+            twofactor_config: self.twofactor_config.clone(),
+        }
     }
+}
 
-    fn sign(
+impl<R: TwoFactorResolver + ?Sized> GASigner<R> {
+    /// Have the server co-sign `psbt`, authorizing `action` with the two factor code. The inputs
+    /// must already be signed by the user.
+    ///
+    /// Signing as a BDK [`Signer`] always authorizes a `SendRawTx`.
+    pub fn sign_with_action(
         &self,
         psbt: &mut psbt::PartiallySignedTransaction,
-        input_index: Option<usize>,
+        action: TwoFactorAction,
     ) -> Result<(), SignerError> {
         let mut tx = psbt.clone().extract_tx();
 
         for (i, p_i) in tx.input.iter_mut().zip(psbt.inputs.iter()) {
+            let redeem_script = p_i.redeem_script.as_ref().ok_or_else(|| {
+                log::error!("missing redeem script for input {}", i.previous_output);
+                SignerError::MissingWitnessScript
+            })?;
             i.script_sig = Builder::new()
-                .push_slice(&p_i.redeem_script.clone().unwrap().to_bytes())
+                .push_slice(&redeem_script.to_bytes())
                 .into_script();
 
             if let Some(sig) = p_i.partial_sigs.values().nth(0) {
@@ -191,28 +921,51 @@ impl<R: TwoFactorResolver> Signer for GASigner<R> {
         let handle = tokio::runtime::Handle::current();
         handle.spawn(async move {
             let method = resolver.get_method(twofactor_config.get_enabled());
-            session
-                .request_2fa_code(method, "send_raw_tx")
-                .await
-                .unwrap();
+            if resolver.needs_request() {
+                if let Err(e) = session.request_2fa_code(method, action).await {
+                    log::error!("unable to request the two factor code: {:?}", e);
+                    // Fails only when the signer is gone, nobody is waiting for the result
+                    let _ = sender.send(Err(SignerError::UserCanceled));
+                    return;
+                }
+            }
             let code = resolver.get_code();
 
-            let twofactor_data = TwoFactorData { code, method };
+            let bump_fee_amount = match action {
+                TwoFactorAction::BumpFee { amount } => Some(amount),
+                _ => None,
+            };
+            let twofactor_data = TwoFactorData {
+                code,
+                method,
+                bump_fee_amount,
+            };
 
             let result = session
                 .sign_raw_tx(serialize_hex(&tx), twofactor_data)
                 .await
                 .map_err(|e| {
-                    println!("{:?}", e);
+                    log::error!("the server refused to sign the transaction: {:?}", e);
 
                     SignerError::UserCanceled
                 });
-            sender.send(result).unwrap();
+            let _ = sender.send(result);
         });
 
-        let signed_tx = receiver.recv().unwrap()?;
-        let signed_tx: Transaction =
-            deserialize(&Vec::<u8>::from_hex(&signed_tx.tx).unwrap()).unwrap();
+        let signed_tx = receiver.recv().map_err(|_| {
+            log::error!("the signing task stopped without a result");
+            SignerError::UserCanceled
+        })??;
+        let signed_tx: Transaction = Vec::<u8>::from_hex(&signed_tx.tx)
+            .ok()
+            .and_then(|tx| deserialize(&tx).ok())
+            .ok_or_else(|| {
+                log::error!(
+                    "invalid signed transaction from the server: {}",
+                    signed_tx.tx
+                );
+                SignerError::UserCanceled
+            })?;
 
         for (psbt_input, signed_input) in psbt.inputs.iter_mut().zip(signed_tx.input.iter()) {
             let service_pk = psbt_input
@@ -220,16 +973,38 @@ impl<R: TwoFactorResolver> Signer for GASigner<R> {
                 .iter()
                 .find(|(_, (fing, _))| fing == &self.service_fingerprint)
                 .map(|(pk, _)| pk.clone())
-                .unwrap();
+                .ok_or(SignerError::MissingHDKeypath)?;
+            // The witness is the empty element of `CHECKMULTISIG`, then the service signature
+            let service_sig = signed_input.witness.get(1).ok_or_else(|| {
+                log::error!(
+                    "missing service signature for {}",
+                    signed_input.previous_output
+                );
+                SignerError::UserCanceled
+            })?;
             psbt_input
                 .partial_sigs
-                .insert(service_pk, signed_input.witness[1].clone());
+                .insert(service_pk, service_sig.clone());
         }
 
         Ok(())
     }
 }
 
+impl<R: TwoFactorResolver + ?Sized> Signer for GASigner<R> {
+    fn sign_whole_tx(&self) -> bool {
+        true
+    }
+
+    fn sign(
+        &self,
+        psbt: &mut psbt::PartiallySignedTransaction,
+        _input_index: Option<usize>,
+    ) -> Result<(), SignerError> {
+        self.sign_with_action(psbt, TwoFactorAction::SendRawTx)
+    }
+}
+
 #[derive(Debug)]
 pub struct GAAddressValidator {
     pub session: Arc<GAClient>,
@@ -274,16 +1049,104 @@ impl AddressValidator for GAAddressValidator {
     }
 }
 
+fn forward_notifications<S, A, K, F>(mut queue: S, events: broadcast::Sender<GreenEvent>, parse: F)
+where
+    S: Stream<Item = (A, K)> + Unpin + Send + 'static,
+    A: Serialize + Send,
+    K: Send,
+    F: Fn(serde_json::Value) -> Result<GreenEvent, serde_json::Error> + Send + 'static,
+{
+    tokio::spawn(async move {
+        while let Some((args, _)) = queue.next().await {
+            let mut args = match serde_json::to_value(&args) {
+                Ok(args) => args,
+                Err(e) => {
+                    log::warn!("invalid notification: {:?}", e);
+                    continue;
+                }
+            };
+            // The payload is the first positional argument, anything else is skipped
+            let payload = match args.get_mut(0) {
+                Some(payload) => payload.take(),
+                None => {
+                    log::warn!("notification without arguments: {}", args);
+                    continue;
+                }
+            };
+
+            match parse(payload) {
+                Ok(event) => {
+                    // Fails only when nobody is listening
+                    let _ = events.send(event);
+                }
+                Err(e) => log::warn!("invalid notification: {:?}", e),
+            }
+        }
+    });
+}
+
 impl fmt::Debug for GAClient {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.auth_response)
+        write!(f, "{:?}", self.auth_response.read().unwrap())
     }
 }
 
-fn get_sign_message_hash(msg: &str) -> Message {
+pub(crate) fn get_sign_message_hash(msg: &str) -> Message {
     let mut answer = b"\x18Bitcoin Signed Message:\n".to_vec();
-    answer.push(msg.len() as u8);
+    answer.extend(serialize(&VarInt(msg.len() as u64)));
     answer.extend_from_slice(msg.as_bytes());
 
     Message::from_slice(&sha256d::Hash::hash(&answer).into_inner()).unwrap()
 }
+
+// The server expects an empty object when no code is needed
+fn twofactor_arg(twofactor_data: Option<TwoFactorData>) -> Result<Arg, Box<dyn Error>> {
+    let twofactor_data = match twofactor_data {
+        Some(data) => serde_json::to_value(&data)?,
+        None => serde_json::json!({}),
+    };
+
+    Ok(serde_json::from_value(twofactor_data)?)
+}
+
+/// Path of the key that signs the login challenge
+pub fn get_login_path() -> DerivationPath {
+    vec![ChildNumber::Normal { index: 0x4741b11e }].into()
+}
+
+// The server derives the `gait_path` from the xpub at `m/18241'`, so we have to compute the same
+// value when registering a new wallet
+fn get_gait_path_bytes(signer: &dyn UserSigner) -> Result<[u8; 64], Box<dyn Error>> {
+    let gait_xpub = signer.get_xpub(&vec![ChildNumber::from_hardened_idx(0x4741)?].into())?;
+
+    let mut engine = HmacEngine::<sha512::Hash>::new(b"GreenAddress.it HD wallet path");
+    engine.input(&gait_xpub.chain_code[..]);
+    engine.input(&gait_xpub.public_key.key.serialize());
+
+    Ok(Hmac::from_engine(engine).into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use bitcoin::Network;
+
+    use crate::signer::SoftwareSigner;
+
+    #[test]
+    fn test_gait_path_bytes() {
+        // Seed of the BIP32 test vector 1, the expected value was computed independently from
+        // the chain code and public key of m/18241'
+        let seed = Vec::<u8>::from_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+        let xprv = ExtendedPrivKey::new_master(Network::Testnet, &seed).unwrap();
+        let signer = SoftwareSigner::new(xprv);
+
+        assert_eq!(
+            get_gait_path_bytes(&signer).unwrap().to_hex(),
+            "f4f8863578462247484a3924a36b9c68a2921df319b66e75999c25d93f5d7005\
+             1168c2d89c53becbb43b026c596f07fcdb7bc1e327158575c3185c0b2ca98d16"
+        );
+    }
+}
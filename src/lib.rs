@@ -0,0 +1,48 @@
+//! Client for Blockstream Green multisig wallets built on top of BDK
+//!
+//! A [`GAClient`] holds the authenticated session with the Green server, every
+//! [`Subaccount`] is a BDK wallet for one of the 2of2 subaccounts of the user, co-signed by the
+//! server through the [`GASigner`] and the codes returned by a [`TwoFactorResolver`].
+//! [`GreenWallet`] groups the subaccounts of a session.
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bdk;
+
+pub mod airgap;
+pub mod batch;
+mod config;
+pub mod daemon;
+pub mod database;
+mod descriptor;
+pub mod export;
+mod fees;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod fiat;
+mod ga;
+pub mod message;
+mod metadata;
+mod proxy;
+pub mod rpc;
+mod signer;
+mod subaccount;
+pub mod sweep;
+mod twofactor;
+pub mod types;
+mod wallet;
+
+pub use config::{BlockchainConfig, GAClientConfig};
+pub use database::DatabaseConfig;
+pub use descriptor::GreenSubaccountDescriptor;
+pub use fees::FeeTarget;
+pub use fiat::{FiatAmount, FiatRate};
+pub use ga::{ConnectionState, GAAddressValidator, GAClient, GASigner};
+pub use metadata::SubaccountMetadata;
+pub use signer::{ExternalSigner, SoftwareSigner, UserSigner};
+pub use subaccount::{CoinControl, HistoryEntry, Subaccount};
+pub use twofactor::{ParamResolver, StdinResolver, TwoFactorResolver};
+pub use types::{GreenEvent, TwoFactorAction, TwoFactorMethod, UtxoStatus};
+pub use wallet::{GreenWallet, SubaccountInfo};
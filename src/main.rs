@@ -1,34 +1,351 @@
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bdk;
-
 use std::error::Error;
-use std::str::FromStr;
+use std::fs::File;
+use std::io::{stdin, stdout, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+
+use bip0039::{Language, Mnemonic, MnemonicType, Seed};
 
-use bip0039::{Language, Mnemonic, Seed};
+use structopt::StructOpt;
 
 use bdk::bitcoin;
 use bdk::electrum_client::Client as ElectrumClient;
 use bdk::sled;
-use bdk::{FeeRate, TxBuilder};
+use bdk::{FeeRate, TransactionDetails, TxBuilder};
+
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Address, Network, OutPoint, Txid};
+
+use bdk::blockchain::{
+    noop_progress, Blockchain, ElectrumBlockchain, EsploraBlockchain, OfflineBlockchain,
+};
+use bdk::database::{BatchDatabase, MemoryDatabase};
+
+use neerg::database::{
+    get_local_namespace, get_namespace, is_encrypted, DatabaseConfig, EncryptedSled, LocalStore,
+};
+use neerg::export::HistoryFormat;
+use neerg::rpc::{RpcAuth, RpcBlockchain, RpcConfig};
+use neerg::{airgap, batch, daemon, export, message, sweep};
+use neerg::{
+    BlockchainConfig, ExternalSigner, FeeTarget, GAClient, GAClientConfig, GreenEvent, GreenWallet,
+    ParamResolver, SoftwareSigner, StdinResolver, Subaccount, TwoFactorResolver, UserSigner,
+    UtxoStatus,
+};
 
-use bitcoin::util::bip32::ExtendedPrivKey;
-use bitcoin::{Network, Txid};
+#[derive(Debug, StructOpt)]
+#[structopt(name = "neerg")]
+struct Opts {
+    /// BIP39 mnemonic of the wallet
+    #[structopt(long, env = "NEERG_MNEMONIC", hide_env_values = true)]
+    mnemonic: Option<String>,
+    /// External signer that holds the user key, instead of the mnemonic
+    #[structopt(long, env = "NEERG_SIGNER")]
+    signer: Option<PathBuf>,
+    /// Xpubs exported by `export-xpubs` on an offline machine, instead of the mnemonic
+    #[structopt(long)]
+    xpubs: Option<PathBuf>,
+    /// Subaccount pointer
+    #[structopt(short, long, default_value = "0")]
+    subaccount: u16,
 
-use bdk::blockchain::{noop_progress, ElectrumBlockchain};
+    /// Green websocket endpoint
+    #[structopt(long, default_value = "wss://testwss.greenaddress.it/v2/ws/")]
+    endpoint: String,
+    /// File where the random device id of this install is stored
+    #[structopt(long, default_value = "neerg-device-id")]
+    device_id_file: PathBuf,
+    /// Timeout in seconds for the calls to the Green server
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+    /// SOCKS5 proxy (`host:port`) for the connections to Green and Electrum, like Tor
+    #[structopt(long, env = "NEERG_PROXY")]
+    proxy: Option<String>,
 
-mod descriptor;
-mod ga;
-mod subaccount;
-mod twofactor;
-mod types;
-mod wallet;
+    /// Blockchain backend: `electrum`, `esplora`, `rpc` (bitcoind) or `offline`
+    #[structopt(long, default_value = "electrum")]
+    backend: String,
+    /// Url of the Electrum server, of the Esplora API or of the bitcoind RPC interface,
+    /// depending on the backend
+    #[structopt(long)]
+    backend_url: Option<String>,
+    /// Username of the bitcoind RPC interface
+    #[structopt(long)]
+    rpc_user: Option<String>,
+    #[structopt(long, env = "NEERG_RPC_PASSWORD", hide_env_values = true)]
+    rpc_password: Option<String>,
+    /// Cookie file of bitcoind, like `~/.bitcoin/testnet3/.cookie`, instead of the user and
+    /// password
+    #[structopt(long)]
+    rpc_cookie: Option<PathBuf>,
+    /// Watch-only wallet created in bitcoind for the subaccounts
+    #[structopt(long, default_value = "neerg")]
+    rpc_wallet: String,
 
-use ga::GAClient;
-use subaccount::Subaccount;
+    /// Local database: `sled` or `memory`
+    #[structopt(long, default_value = "sled")]
+    database: String,
+    /// Path of the sled database
+    #[structopt(long, default_value = "magical-db")]
+    database_path: PathBuf,
+    /// Encrypt the sled database with this passphrase
+    #[structopt(long, env = "NEERG_DATABASE_PASSPHRASE", hide_env_values = true)]
+    database_passphrase: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+struct TxOpts {
+    /// Recipient address, defaults to a new address of the subaccount
+    #[structopt(long)]
+    to: Option<Address>,
+    /// Amount in satoshi, sends the whole balance when omitted
+    #[structopt(long)]
+    amount: Option<u64>,
+    /// Spend only these outputs, as `txid:vout`
+    #[structopt(long = "utxo")]
+    utxos: Vec<OutPoint>,
+    #[structopt(flatten)]
+    fee: FeeOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct FeeOpts {
+    /// Fee rate in sat/vbyte, overrides the fee target
+    #[structopt(long)]
+    fee_rate: Option<f32>,
+    /// Use the server estimate for `fast`, `medium` or `slow` confirmation, or within a number of
+    /// blocks
+    #[structopt(long, default_value = "medium")]
+    fee_target: FeeTarget,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Register the mnemonic on the server, generating a new one if not provided
+    Register,
+    /// Print the balance of the subaccount
+    Balance,
+    /// Create, sign and broadcast a transaction
+    Send {
+        #[structopt(flatten)]
+        tx: TxOpts,
+    },
+    /// Print the fee rates estimated by the server
+    Fees,
+    /// List the subaccounts with their balance and local metadata
+    Subaccounts {
+        /// Include the archived subaccounts
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Rename the subaccount on the server
+    RenameSubaccount { name: String },
+    /// Hide the subaccount from the listings, it can still receive funds
+    Archive,
+    /// Show an archived subaccount in the listings again
+    Unarchive,
+    /// Change the local metadata of the subaccount, an empty value clears it
+    SetMetadata {
+        /// Replace the labels, can be repeated
+        #[structopt(long = "label")]
+        labels: Vec<String>,
+        /// Remove every label
+        #[structopt(long)]
+        clear_labels: bool,
+        /// Colour as `#rrggbb`
+        #[structopt(long)]
+        color: Option<String>,
+        #[structopt(long)]
+        purpose: Option<String>,
+    },
+    /// Print the fiat currencies available for every exchange and the current rate
+    Currencies,
+    /// Print the account settings stored on the server
+    Settings,
+    /// Change the account settings, nLockTime and CSV time changes require 2FA
+    ChangeSettings {
+        /// `BTC`, `mBTC`, `µBTC`, `bits` or `sats`
+        #[structopt(long)]
+        unit: Option<String>,
+        /// Fiat currency, like `USD`
+        #[structopt(long)]
+        currency: Option<String>,
+        /// Exchange the fiat rate comes from, like `BITSTAMP`
+        #[structopt(long)]
+        exchange: Option<String>,
+        /// Email notifications for incoming transactions
+        #[structopt(long)]
+        email_incoming: Option<bool>,
+        /// Email notifications for outgoing transactions
+        #[structopt(long)]
+        email_outgoing: Option<bool>,
+        /// Confirmation target of the default fee rate, in blocks
+        #[structopt(long)]
+        required_num_blocks: Option<u32>,
+        /// Fee rate in sat/vbyte used instead of the estimates, 0 to go back to the estimates
+        #[structopt(long)]
+        custom_fee_rate: Option<f32>,
+        /// Minutes of inactivity before logging out
+        #[structopt(long)]
+        altimeout: Option<u32>,
+        /// Blocks after which the nLockTime recovery transactions become valid
+        #[structopt(long)]
+        nlocktime: Option<u32>,
+        /// Blocks after which the CSV recovery path can be used
+        #[structopt(long)]
+        csvtime: Option<u32>,
+    },
+    /// Ask the server to email the nLockTime recovery transactions
+    SendNlocktimes,
+    /// Move funds to another subaccount of the wallet
+    Transfer {
+        /// Destination subaccount
+        #[structopt(long)]
+        to_subaccount: u16,
+        /// Amount in satoshi, moves the whole balance when omitted
+        #[structopt(long)]
+        amount: Option<u64>,
+        /// Spend only these outputs, as `txid:vout`
+        #[structopt(long = "utxo")]
+        utxos: Vec<OutPoint>,
+        #[structopt(flatten)]
+        fee: FeeOpts,
+    },
+    /// Send the funds of a single-sig WIF key or BIP39 mnemonic to the subaccount
+    Sweep {
+        /// WIF private key or BIP39 mnemonic
+        #[structopt(env = "NEERG_SWEEP_KEY", hide_env_values = true)]
+        key: String,
+        /// BIP39 passphrase of the mnemonic
+        #[structopt(long, default_value = "")]
+        passphrase: String,
+        #[structopt(flatten)]
+        fee: FeeOpts,
+    },
+    /// List the unspent outputs of the subaccount with their status
+    ListUnspent,
+    /// Exclude outputs from coin selection
+    Freeze {
+        outpoints: Vec<OutPoint>,
+        /// Store the status in the local database instead of on the server
+        #[structopt(long)]
+        local: bool,
+    },
+    /// Make frozen outputs spendable again
+    Unfreeze {
+        outpoints: Vec<OutPoint>,
+        /// Store the status in the local database instead of on the server
+        #[structopt(long)]
+        local: bool,
+    },
+    /// Pay many recipients with a single transaction
+    BatchSend {
+        /// CSV or JSON file with the recipients, as address and amount pairs or BIP21 URIs
+        file: PathBuf,
+        #[structopt(flatten)]
+        fee: FeeOpts,
+        /// Don't ask for confirmation after printing the summary
+        #[structopt(long)]
+        yes: bool,
+    },
+    /// Replace an unconfirmed transaction with one paying a higher fee
+    BumpFee {
+        txid: Txid,
+        #[structopt(flatten)]
+        fee: FeeOpts,
+    },
+    /// Spend an unconfirmed incoming transaction with a fee high enough to confirm both
+    Cpfp {
+        txid: Txid,
+        #[structopt(flatten)]
+        fee: FeeOpts,
+    },
+    /// List the transactions of the subaccount with their memos
+    History,
+    /// Set the memo of a transaction on the server
+    SetMemo { txid: Txid, memo: String },
+    /// Export the transaction history of the subaccount for accounting
+    ExportHistory {
+        /// Either `csv` or `json`
+        #[structopt(long, default_value = "csv")]
+        format: HistoryFormat,
+        #[structopt(long)]
+        out: Option<PathBuf>,
+    },
+    /// Print the server notifications, syncing the subaccount when it receives a transaction
+    Watch,
+    /// Serve JSON-RPC over HTTP for the main account and every subaccount
+    Daemon {
+        /// Address to listen on
+        #[structopt(long, default_value = "127.0.0.1:18732")]
+        listen: String,
+        /// File where the token that authenticates the requests is written, readable only by
+        /// the current user
+        #[structopt(long, default_value = "neerg-daemon.cookie")]
+        cookie_file: PathBuf,
+    },
+    /// Create an unsigned PSBT for the offline machine
+    CreatePsbt {
+        #[structopt(flatten)]
+        tx: TxOpts,
+        /// Write the PSBT to this file instead of printing it as base64
+        #[structopt(long)]
+        out: Option<PathBuf>,
+    },
+    /// Add the user signatures to a PSBT, doesn't need to be online
+    SignPsbt {
+        /// Base64 PSBT or path to a PSBT file
+        psbt: String,
+        /// Write the PSBT to this file instead of printing it as base64
+        #[structopt(long)]
+        out: Option<PathBuf>,
+    },
+    /// Add the service signature to a PSBT signed offline and broadcast it
+    FinalizePsbt {
+        /// Base64 PSBT or path to a PSBT file
+        psbt: String,
+    },
+    /// Export the xpubs needed by an online machine paired with this one
+    ExportXpubs {
+        /// Number of subaccounts to export
+        #[structopt(long, default_value = "10")]
+        subaccounts: u16,
+        #[structopt(long)]
+        out: Option<PathBuf>,
+    },
+    /// Export the descriptors of every subaccount for external watch-only wallets
+    ExportDescriptors {
+        /// Format the output for Bitcoin Core's `importdescriptors`
+        #[structopt(long)]
+        core: bool,
+        /// Last derivation index to import in Bitcoin Core
+        #[structopt(long, default_value = "1000")]
+        range: u32,
+    },
+    /// Sign a message with the user key of an address of the subaccount, or with the login key
+    /// when no address is given
+    SignAddressMessage {
+        #[structopt(long)]
+        address: Option<Address>,
+        message: String,
+    },
+    /// Verify a signed message, for single-sig addresses or addresses of the subaccount
+    VerifyMessage {
+        address: Address,
+        signature: String,
+        message: String,
+    },
+    /// Sign a message with the key at `path`, used to login from the online machine
+    SignMessage {
+        path: DerivationPath,
+        message: String,
+    },
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
@@ -43,40 +360,243 @@ fn main() -> Result<(), Box<dyn Error>> {
     runtime.block_on(async { async_main().await })
 }
 
-async fn async_main() -> Result<(), Box<dyn Error>> {
-    let mnemonic_bip39 = Mnemonic::from_phrase("rabbit okay wrestle addict barrel tribe cricket cradle web flavor harbor hour mammal earth else silly prefer mimic visa drill prison film wait vendor", Language::English)?;
+fn get_xprv(mnemonic: &str) -> Result<ExtendedPrivKey, Box<dyn Error>> {
+    let mnemonic_bip39 = Mnemonic::from_phrase(mnemonic, Language::English)?;
     let seed = Seed::new(&mnemonic_bip39, "");
     let seed_bytes: &[u8] = seed.as_bytes();
-    let xprv = ExtendedPrivKey::new_master(Network::Testnet, seed_bytes)?;
 
-    let session = Arc::new(GAClient::new(&xprv).await?);
+    Ok(ExtendedPrivKey::new_master(Network::Testnet, seed_bytes)?)
+}
+
+fn get_signer(opts: &Opts) -> Result<Arc<dyn UserSigner>, Box<dyn Error>> {
+    if let Some(xpubs) = &opts.xpubs {
+        Ok(Arc::new(airgap::AirGappedSigner::from_file(
+            xpubs,
+            BufReader::new(stdin()),
+            stdout(),
+        )?))
+    } else if let Some(command) = &opts.signer {
+        Ok(Arc::new(ExternalSigner::new(command, vec![])))
+    } else if let Some(mnemonic) = &opts.mnemonic {
+        Ok(Arc::new(SoftwareSigner::new(get_xprv(mnemonic)?)))
+    } else {
+        Err("one of `--mnemonic`, `--signer` or `--xpubs` is required".into())
+    }
+}
+
+/// Write the PSBT in binary form to `out`, or print it as base64 when no file is given
+fn export_psbt(
+    psbt: &PartiallySignedTransaction,
+    out: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    match out {
+        Some(out) => airgap::export_psbt(psbt, File::create(out)?, true),
+        None => airgap::export_psbt(psbt, stdout(), false),
+    }
+}
+
+fn get_config(opts: &Opts) -> Result<GAClientConfig, Box<dyn Error>> {
+    let mut config = GAClientConfig::new()
+        .endpoint(&opts.endpoint)
+        .call_timeout(Duration::from_secs(opts.timeout))
+        .persisted_device_id(&opts.device_id_file)?;
+    if let Some(proxy) = &opts.proxy {
+        config = config.proxy(proxy);
+    }
+
+    Ok(config)
+}
+
+fn get_blockchain_config(
+    opts: &Opts,
+    signer: &dyn UserSigner,
+    session: &GAClient,
+) -> Result<BlockchainConfig, Box<dyn Error>> {
+    let url = opts.backend_url.clone();
+
+    match opts.backend.as_str() {
+        "electrum" => Ok(BlockchainConfig::Electrum {
+            url: url.unwrap_or_else(|| "ssl://electrum.blockstream.info:60002".into()),
+            socks5: opts.proxy.clone(),
+        }),
+        "esplora" => Ok(BlockchainConfig::Esplora {
+            base_url: url.unwrap_or_else(|| "https://blockstream.info/testnet/api".into()),
+        }),
+        "rpc" => {
+            let auth = match (&opts.rpc_user, &opts.rpc_password, &opts.rpc_cookie) {
+                (Some(user), Some(password), None) => RpcAuth::UserPass {
+                    user: user.clone(),
+                    password: password.clone(),
+                },
+                (None, None, Some(cookie)) => RpcAuth::Cookie(cookie.clone()),
+                _ => return Err("use either the RPC user and password or the cookie file".into()),
+            };
+            let descriptors = export::get_descriptors(signer, session, Network::Testnet)?
+                .into_iter()
+                .map(|d| d.descriptor)
+                .collect();
+
+            Ok(BlockchainConfig::Rpc(RpcConfig {
+                url: url.unwrap_or_else(|| "http://127.0.0.1:18332".into()),
+                auth,
+                wallet_name: opts.rpc_wallet.clone(),
+                rescan_since: session.get_earliest_key_creation_time(),
+                descriptors,
+                network: Network::Testnet,
+            }))
+        }
+        "offline" => Ok(BlockchainConfig::Offline),
+        backend => Err(format!("unknown backend `{}`", backend).into()),
+    }
+}
+
+fn get_database_config(opts: &Opts) -> Result<DatabaseConfig, Box<dyn Error>> {
+    match (opts.database.as_str(), &opts.database_passphrase) {
+        ("sled", None) => Ok(DatabaseConfig::Sled {
+            path: opts.database_path.clone(),
+        }),
+        ("sled", Some(passphrase)) => Ok(DatabaseConfig::EncryptedSled {
+            path: opts.database_path.clone(),
+            passphrase: passphrase.clone(),
+        }),
+        ("memory", _) => Ok(DatabaseConfig::Memory),
+        (database, _) => Err(format!("unknown database `{}`", database).into()),
+    }
+}
+
+async fn open_subaccount<B, D, F>(
+    signer: &Arc<dyn UserSigner>,
+    session: &Arc<GAClient>,
+    pointer: u16,
+    client: &Arc<B>,
+    open_database: &F,
+    resolver: Arc<dyn TwoFactorResolver>,
+) -> Result<Subaccount<B, D>, Box<dyn Error>>
+where
+    B: Blockchain,
+    D: BatchDatabase,
+    F: Fn(&str) -> Result<(D, LocalStore), Box<dyn Error>>,
+{
     let twofactor_config = session.get_2fa_config().await?;
 
-    let electrum_client = Arc::new(ElectrumBlockchain::from(
-        ElectrumClient::new("ssl://electrum.blockstream.info:60002", None).unwrap(),
-    ));
-    let database = Arc::new(sled::open("magical-db")?);
+    let namespace = get_namespace(Network::Testnet, signer.get_fingerprint()?, pointer);
+    let (database, local) = open_database(&namespace)?;
 
     let subaccount = Subaccount::new(
-        &xprv,
-        session.get_gait_path(),
-        0,
-        &database,
-        &electrum_client,
-        &session,
-        twofactor_config.clone(),
-    )?;
-    subaccount.sync(noop_progress(), None)?;
-
-    println!("balance: {}", subaccount.get_balance()?);
-
-    let (psbt, details) = subaccount.create_tx(
-        TxBuilder::with_recipients(vec![(subaccount.get_new_address()?.script_pubkey(), 0)])
-            .enable_rbf()
-            .send_all(),
-    )?;
-    // let (psbt, details) = subaccount.bump_fee(&Txid::from_str("0998044636fa8a4bf5c72136f18578b517b553755ebd0bf1fa94159dffca58e4").unwrap(), TxBuilder::new().send_all().enable_rbf().fee_rate(FeeRate::from_sat_per_vb(2.5)))?;
+        signer,
+        &session.get_gait_path(),
+        pointer,
+        database,
+        client,
+        session,
+        resolver,
+        twofactor_config,
+    )?
+    .with_local_store(local);
+    match subaccount.sync(noop_progress(), None) {
+        Ok(()) | Err(bdk::Error::OfflineClient) => {}
+        Err(e) => return Err(e.into()),
+    }
+    subaccount.refresh_utxo_status().await?;
+
+    Ok(subaccount)
+}
+
+/// Open the main account and every subaccount known by the server
+async fn open_wallet<B, D, F>(
+    signer: &Arc<dyn UserSigner>,
+    session: &Arc<GAClient>,
+    client: &Arc<B>,
+    open_database: &F,
+    resolver: Arc<dyn TwoFactorResolver>,
+) -> Result<GreenWallet<B, D>, Box<dyn Error>>
+where
+    B: Blockchain,
+    D: BatchDatabase,
+    F: Fn(&str) -> Result<(D, LocalStore), Box<dyn Error>>,
+{
+    let mut wallet = GreenWallet::new(Arc::clone(session));
+    let pointers =
+        std::iter::once(0).chain(session.get_subaccounts().into_iter().map(|s| s.pointer));
+    for pointer in pointers {
+        let subaccount = open_subaccount(
+            signer,
+            session,
+            pointer,
+            client,
+            open_database,
+            Arc::clone(&resolver),
+        )
+        .await?;
+        wallet.add_subaccount(pointer, subaccount);
+    }
+
+    Ok(wallet)
+}
+
+fn create_tx<B: Blockchain, D: BatchDatabase>(
+    subaccount: &Subaccount<B, D>,
+    session: &GAClient,
+    tx: &TxOpts,
+) -> Result<(PartiallySignedTransaction, TransactionDetails), Box<dyn Error>> {
+    let to = match &tx.to {
+        Some(address) if address.network != Network::Testnet => {
+            return Err("the recipient address is for the wrong network".into())
+        }
+        Some(address) => address.script_pubkey(),
+        None => subaccount.get_new_address()?.script_pubkey(),
+    };
+
+    let coin_control = subaccount.get_coin_control(&tx.utxos)?;
+    let mut builder = TxBuilder::with_recipients(vec![(to, tx.amount.unwrap_or(0))])
+        .utxos(coin_control.utxos)
+        .unspendable(coin_control.unspendable)
+        .enable_rbf()
+        .fee_rate(get_fee_rate(session, &tx.fee)?);
+    if tx.amount.is_none() {
+        builder = builder.send_all();
+    }
+
+    Ok(subaccount.create_tx(builder)?)
+}
+
+fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    print!("{} [y/N] ", question);
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+fn get_fee_rate(session: &GAClient, fee: &FeeOpts) -> Result<FeeRate, Box<dyn Error>> {
+    // The server refuses to sign transactions below its minimum relay fee
+    let min_fee_rate = session.get_min_fee_rate();
+    match fee.fee_rate {
+        Some(fee_rate) if fee_rate < min_fee_rate.as_sat_vb() => Err(format!(
+            "the fee rate is below the server minimum of {} sat/vbyte",
+            min_fee_rate.as_sat_vb()
+        )
+        .into()),
+        Some(fee_rate) => Ok(FeeRate::from_sat_per_vb(fee_rate)),
+        None => Ok(session.get_fee_rate(fee.fee_target)),
+    }
+}
+
+fn sign_and_broadcast<B: Blockchain, D: BatchDatabase>(
+    subaccount: &Subaccount<B, D>,
+    psbt: PartiallySignedTransaction,
+) -> Result<(), Box<dyn Error>> {
     let (psbt, finalized) = subaccount.sign(psbt, None)?;
+    broadcast(subaccount, psbt, finalized)
+}
+
+fn broadcast<B: Blockchain, D: BatchDatabase>(
+    subaccount: &Subaccount<B, D>,
+    psbt: PartiallySignedTransaction,
+    finalized: bool,
+) -> Result<(), Box<dyn Error>> {
     println!("finalized = {}", finalized);
 
     let txid = subaccount.broadcast(psbt.extract_tx())?;
@@ -84,3 +604,552 @@ async fn async_main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+async fn async_main() -> Result<(), Box<dyn Error>> {
+    let mut opts = Opts::from_args();
+
+    // Commands that don't need to open a subaccount, most of them can also run offline
+    match &opts.command {
+        Command::SignPsbt { psbt, out } => {
+            let mut psbt = airgap::import_psbt(psbt)?;
+            let signer = get_signer(&opts)?;
+            signer.sign_psbt(&mut psbt)?;
+            airgap::check_signed_psbt(&psbt, signer.get_fingerprint()?)?;
+
+            return export_psbt(&psbt, out.as_deref());
+        }
+        Command::ExportXpubs { subaccounts, out } => {
+            let xpubs = airgap::export_xpubs(get_signer(&opts)?.as_ref(), *subaccounts)?;
+            match out {
+                Some(out) => std::fs::write(out, xpubs)?,
+                None => println!("{}", xpubs),
+            }
+
+            return Ok(());
+        }
+        Command::SignMessage { path, message } => {
+            let signature = get_signer(&opts)?.sign_message(path, message)?;
+            println!("{}", base64::encode(&signature.serialize_compact()[..]));
+
+            return Ok(());
+        }
+        Command::ExportDescriptors { core, range } => {
+            let signer = get_signer(&opts)?;
+            let session = GAClient::new(get_config(&opts)?, Arc::clone(&signer)).await?;
+
+            let descriptors = export::get_descriptors(signer.as_ref(), &session, Network::Testnet)?;
+            match core {
+                true => println!(
+                    "{}",
+                    export::export_core_descriptors(
+                        &descriptors,
+                        session.get_earliest_key_creation_time(),
+                        *range
+                    )?
+                ),
+                false => {
+                    for d in descriptors {
+                        println!("{} ({}): {}", d.name, d.pointer, d.descriptor);
+                    }
+                }
+            }
+
+            return Ok(());
+        }
+        Command::Register if opts.mnemonic.is_none() => {
+            let mnemonic = Mnemonic::new(MnemonicType::Words24, Language::English);
+            println!("generated mnemonic: {}", mnemonic.phrase());
+
+            opts.mnemonic = Some(mnemonic.phrase().to_string());
+        }
+        _ => {}
+    }
+
+    let signer = get_signer(&opts)?;
+    let session = match opts.command {
+        Command::Register => {
+            Arc::new(GAClient::register(get_config(&opts)?, Arc::clone(&signer)).await?)
+        }
+        _ => Arc::new(GAClient::new(get_config(&opts)?, Arc::clone(&signer)).await?),
+    };
+    GAClient::spawn_supervisor(&session);
+
+    match get_database_config(&opts)? {
+        DatabaseConfig::Sled { path } => {
+            let db = sled::open(path)?;
+            if is_encrypted(&db) {
+                return Err("the database is encrypted, set its passphrase".into());
+            }
+            let open_database = |name: &str| -> Result<_, Box<dyn Error>> {
+                let local = db.open_tree(get_local_namespace(name))?;
+                Ok((db.open_tree(name)?, LocalStore::from(local)))
+            };
+
+            with_blockchain(&opts, &signer, &session, open_database).await
+        }
+        DatabaseConfig::EncryptedSled { path, passphrase } => {
+            let db = EncryptedSled::open(path, &passphrase)?;
+            let open_database = |name: &str| -> Result<_, Box<dyn Error>> {
+                let local = db.open_tree(&get_local_namespace(name))?;
+                Ok((db.open_tree(name)?, LocalStore::from(local)))
+            };
+
+            with_blockchain(&opts, &signer, &session, open_database).await
+        }
+        DatabaseConfig::Memory => {
+            let open_database = |_: &str| -> Result<_, Box<dyn Error>> {
+                Ok((MemoryDatabase::new(), LocalStore::memory()))
+            };
+
+            with_blockchain(&opts, &signer, &session, open_database).await
+        }
+    }
+}
+
+async fn with_blockchain<D, F>(
+    opts: &Opts,
+    signer: &Arc<dyn UserSigner>,
+    session: &Arc<GAClient>,
+    open_database: F,
+) -> Result<(), Box<dyn Error>>
+where
+    D: BatchDatabase,
+    F: Fn(&str) -> Result<(D, LocalStore), Box<dyn Error>>,
+{
+    match get_blockchain_config(opts, signer.as_ref(), session)? {
+        BlockchainConfig::Electrum { url, socks5 } => {
+            let client = ElectrumClient::new(&url, socks5.as_deref())?;
+            let client = Arc::new(ElectrumBlockchain::from(client));
+
+            run(opts, signer, session, client, open_database).await
+        }
+        BlockchainConfig::Esplora { base_url } => {
+            let client = Arc::new(EsploraBlockchain::new(&base_url));
+
+            run(opts, signer, session, client, open_database).await
+        }
+        BlockchainConfig::Rpc(config) => {
+            let client = Arc::new(RpcBlockchain::new(config));
+
+            run(opts, signer, session, client, open_database).await
+        }
+        BlockchainConfig::Offline => {
+            let client = Arc::new(OfflineBlockchain);
+
+            run(opts, signer, session, client, open_database).await
+        }
+    }
+}
+
+async fn run<B, D, F>(
+    opts: &Opts,
+    signer: &Arc<dyn UserSigner>,
+    session: &Arc<GAClient>,
+    client: Arc<B>,
+    open_database: F,
+) -> Result<(), Box<dyn Error>>
+where
+    B: Blockchain,
+    D: BatchDatabase,
+    F: Fn(&str) -> Result<(D, LocalStore), Box<dyn Error>>,
+{
+    match &opts.command {
+        Command::Daemon {
+            listen,
+            cookie_file,
+        } => {
+            // The codes come with the requests, so every subaccount shares the same resolver
+            let resolver = Arc::new(ParamResolver::default());
+            let wallet = open_wallet(
+                signer,
+                session,
+                &client,
+                &open_database,
+                Arc::clone(&resolver) as Arc<dyn TwoFactorResolver>,
+            )
+            .await?;
+
+            return daemon::Daemon::new(&wallet, resolver, cookie_file)
+                .serve(listen)
+                .await;
+        }
+        Command::Subaccounts { all } => {
+            let wallet = open_wallet(
+                signer,
+                session,
+                &client,
+                &open_database,
+                Arc::new(StdinResolver),
+            )
+            .await?;
+            for info in wallet.list_subaccounts(*all)? {
+                let fiat_balance = info
+                    .fiat_balance
+                    .map(|f| format!(" ({})", f))
+                    .unwrap_or_default();
+                println!(
+                    "{} {}{}: {} sat{}",
+                    info.pointer,
+                    info.name,
+                    if info.hidden { " [archived]" } else { "" },
+                    info.balance,
+                    fiat_balance
+                );
+
+                let metadata = info.metadata;
+                if !metadata.labels.is_empty() {
+                    println!("  labels: {}", metadata.labels.join(", "));
+                }
+                if let Some(color) = metadata.color {
+                    println!("  colour: {}", color);
+                }
+                if let Some(purpose) = metadata.purpose {
+                    println!("  purpose: {}", purpose);
+                }
+            }
+
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let subaccount = open_subaccount(
+        signer,
+        session,
+        opts.subaccount,
+        &client,
+        &open_database,
+        Arc::new(StdinResolver),
+    )
+    .await?;
+
+    match &opts.command {
+        Command::Register | Command::Balance => {
+            println!("balance: {}", subaccount.get_balance()?);
+            if let Some(fiat_balance) = subaccount.get_fiat_balance()? {
+                println!("fiat balance: {}", fiat_balance);
+            }
+        }
+        Command::Send { tx } => {
+            let (psbt, _details) = create_tx(&subaccount, session, tx)?;
+            sign_and_broadcast(&subaccount, psbt)?;
+        }
+        Command::Fees => {
+            for target in &[FeeTarget::Fast, FeeTarget::Medium, FeeTarget::Slow] {
+                println!(
+                    "{} ({} blocks): {} sat/vbyte",
+                    target,
+                    target.get_blocks(),
+                    session.get_fee_rate(*target).as_sat_vb()
+                );
+            }
+            println!(
+                "minimum: {} sat/vbyte",
+                session.get_min_fee_rate().as_sat_vb()
+            );
+        }
+        Command::RenameSubaccount { name } => {
+            session.rename_subaccount(opts.subaccount, name).await?;
+        }
+        Command::Archive => {
+            session.set_subaccount_hidden(opts.subaccount, true).await?;
+        }
+        Command::Unarchive => {
+            session
+                .set_subaccount_hidden(opts.subaccount, false)
+                .await?;
+        }
+        Command::SetMetadata {
+            labels,
+            clear_labels,
+            color,
+            purpose,
+        } => {
+            let mut metadata = subaccount.get_metadata()?;
+            if *clear_labels {
+                metadata.labels.clear();
+            }
+            if !labels.is_empty() {
+                metadata.labels = labels.clone();
+            }
+            let non_empty = |value: &String| Some(value.clone()).filter(|v| !v.is_empty());
+            if let Some(color) = color {
+                metadata.color = non_empty(color);
+            }
+            if let Some(purpose) = purpose {
+                metadata.purpose = non_empty(purpose);
+            }
+
+            subaccount.set_metadata(metadata)?;
+        }
+        Command::Currencies => {
+            let currencies = session.get_available_currencies().await?;
+            let mut exchanges = currencies.per_exchange.into_iter().collect::<Vec<_>>();
+            exchanges.sort();
+            for (exchange, currencies) in exchanges {
+                println!("{}: {}", exchange, currencies.join(", "));
+            }
+
+            match session.refresh_fiat_rate().await? {
+                Some(rate) => println!(
+                    "current rate: {} {}/BTC from {}",
+                    rate.rate, rate.currency, rate.exchange
+                ),
+                None => println!("no fiat rate for the current pricing source"),
+            }
+        }
+        Command::Settings => {
+            println!("{}", serde_json::to_string_pretty(&session.get_settings())?);
+        }
+        Command::ChangeSettings {
+            unit,
+            currency,
+            exchange,
+            email_incoming,
+            email_outgoing,
+            required_num_blocks,
+            custom_fee_rate,
+            altimeout,
+            nlocktime,
+            csvtime,
+        } => {
+            let mut settings = session.get_settings();
+            let appearance = &mut settings.appearance;
+            if let Some(unit) = unit {
+                appearance.unit = unit.clone();
+            }
+            if let Some(email_incoming) = email_incoming {
+                appearance.notifications_settings.email_incoming = *email_incoming;
+            }
+            if let Some(email_outgoing) = email_outgoing {
+                appearance.notifications_settings.email_outgoing = *email_outgoing;
+            }
+            if let Some(required_num_blocks) = required_num_blocks {
+                appearance.required_num_blocks = *required_num_blocks;
+            }
+            match custom_fee_rate {
+                Some(rate) if *rate == 0.0 => appearance.custom_fee_rate = None,
+                // Stored in sat/kvB like the server fees
+                Some(rate) => appearance.custom_fee_rate = Some((rate * 1000.0) as u64),
+                None => {}
+            }
+            if let Some(altimeout) = altimeout {
+                appearance.altimeout = *altimeout;
+            }
+            if let Some(currency) = currency {
+                settings.pricing.currency = currency.clone();
+            }
+            if let Some(exchange) = exchange {
+                settings.pricing.exchange = exchange.clone();
+            }
+            if let Some(nlocktime) = nlocktime {
+                settings.nlocktime = *nlocktime;
+            }
+            if let Some(csvtime) = csvtime {
+                settings.csvtime = *csvtime;
+            }
+
+            session.change_settings(&settings, &StdinResolver).await?;
+            println!("{}", serde_json::to_string_pretty(&session.get_settings())?);
+        }
+        Command::SendNlocktimes => session.send_nlocktimes().await?,
+        Command::Transfer {
+            to_subaccount,
+            amount,
+            utxos,
+            fee,
+        } => {
+            let destination = open_subaccount(
+                signer,
+                session,
+                *to_subaccount,
+                &client,
+                &open_database,
+                Arc::new(StdinResolver),
+            )
+            .await?;
+
+            let mut wallet = GreenWallet::new(Arc::clone(session));
+            wallet.add_subaccount(opts.subaccount, subaccount);
+            wallet.add_subaccount(*to_subaccount, destination);
+
+            let details = wallet
+                .transfer(
+                    opts.subaccount,
+                    *to_subaccount,
+                    *amount,
+                    get_fee_rate(session, fee)?,
+                    utxos,
+                )
+                .await?;
+            println!("{} fee: {} sat", details.txid, details.fees);
+        }
+        Command::Sweep {
+            key,
+            passphrase,
+            fee,
+        } => {
+            let key = sweep::SweepKey::parse(key, passphrase, Network::Testnet)?;
+            let fee_rate = get_fee_rate(session, fee)?;
+
+            for txid in sweep::sweep(&key, Network::Testnet, &client, &subaccount, fee_rate)? {
+                println!("https://blockstream.info/testnet/tx/{}", txid);
+            }
+        }
+        Command::SignAddressMessage { address, message } => match address {
+            Some(address) => println!("{}", subaccount.sign_message(address, message)?),
+            None => {
+                let (address, signature) =
+                    message::sign_login_message(signer.as_ref(), Network::Testnet, message)?;
+                println!("address: {}\nsignature: {}", address, signature);
+            }
+        },
+        Command::VerifyMessage {
+            address,
+            signature,
+            message,
+        } => {
+            // Addresses that aren't ours can only be checked as single-sig
+            let valid = match subaccount.is_mine(&address.script_pubkey())? {
+                true => subaccount.verify_message(address, signature, message)?,
+                false => message::verify_message(address, signature, message)?,
+            };
+            println!("valid: {}", valid);
+        }
+        Command::ListUnspent => {
+            for (utxo, status) in subaccount.list_unspent_with_status()? {
+                println!("{} {} sat {:?}", utxo.outpoint, utxo.txout.value, status);
+            }
+        }
+        Command::Freeze { outpoints, local } => {
+            subaccount
+                .set_utxo_status(outpoints, UtxoStatus::Frozen, !local)
+                .await?;
+        }
+        Command::Unfreeze { outpoints, local } => {
+            subaccount
+                .set_utxo_status(outpoints, UtxoStatus::Default, !local)
+                .await?;
+        }
+        Command::BatchSend { file, fee, yes } => {
+            let recipients =
+                batch::parse_recipients(&std::fs::read_to_string(file)?, Network::Testnet)?;
+            let coin_control = subaccount.get_coin_control(&[])?;
+            let builder = TxBuilder::with_recipients(
+                recipients
+                    .iter()
+                    .map(|r| (r.address.script_pubkey(), r.amount))
+                    .collect(),
+            )
+            .unspendable(coin_control.unspendable)
+            .enable_rbf()
+            .fee_rate(get_fee_rate(session, fee)?);
+            let (psbt, details) = subaccount.create_tx(builder)?;
+
+            for recipient in &recipients {
+                println!("{} {} sat", recipient.address, recipient.amount);
+            }
+            println!(
+                "{} recipients, total: {} sat, fee: {} sat",
+                recipients.len(),
+                recipients.iter().map(|r| r.amount).sum::<u64>(),
+                details.fees
+            );
+            if !yes && !confirm("Send?")? {
+                return Ok(());
+            }
+
+            sign_and_broadcast(&subaccount, psbt)?;
+        }
+        Command::BumpFee { txid, fee } => {
+            let (psbt, details) = subaccount.bump_fee(txid, get_fee_rate(session, fee)?)?;
+            println!("new fee: {} sat", details.fees);
+
+            let (psbt, finalized) = subaccount.sign_bump(psbt, txid, &details)?;
+            broadcast(&subaccount, psbt, finalized)?;
+        }
+        Command::Cpfp { txid, fee } => {
+            let (psbt, details) = subaccount.cpfp(txid, get_fee_rate(session, fee)?).await?;
+            println!("child fee: {} sat", details.fees);
+
+            sign_and_broadcast(&subaccount, psbt)?;
+        }
+        Command::History => {
+            let fiat_rate = session.get_fiat_rate();
+            for entry in subaccount.get_history(false).await? {
+                let details = &entry.details;
+                let fiat_net = fiat_rate
+                    .as_ref()
+                    .map(|rate| {
+                        let net = details.received as i64 - details.sent as i64;
+                        format!(" fiat: {}", rate.to_fiat(net))
+                    })
+                    .unwrap_or_default();
+                println!(
+                    "{} received: {} sent: {} fees: {} height: {:?}{} memo: {}",
+                    details.txid,
+                    details.received,
+                    details.sent,
+                    details.fees,
+                    details.height,
+                    fiat_net,
+                    entry.memo.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Command::SetMemo { txid, memo } => {
+            subaccount.set_memo(txid, memo).await?;
+        }
+        Command::ExportHistory { format, out } => {
+            let records = export::get_history_records(
+                &subaccount,
+                Network::Testnet,
+                client.get_height().ok(),
+                session.get_fiat_rate().as_ref(),
+            )
+            .await?;
+            let history = export::export_history(&records, *format)?;
+            match out {
+                Some(out) => std::fs::write(out, history)?,
+                None => print!("{}", history),
+            }
+        }
+        Command::Watch => {
+            let mut wallet = GreenWallet::new(Arc::clone(session));
+            wallet.add_subaccount(opts.subaccount, subaccount);
+
+            wallet
+                .watch(|event| {
+                    println!("{:?}", event);
+
+                    if let GreenEvent::Transaction(_) = event {
+                        let subaccount = wallet.get_subaccount(opts.subaccount).unwrap();
+                        match subaccount.get_balance() {
+                            Ok(balance) => println!("balance: {}", balance),
+                            Err(e) => println!("{:?}", e),
+                        }
+                    }
+                })
+                .await?;
+        }
+        Command::CreatePsbt { tx, out } => {
+            let (psbt, _details) = create_tx(&subaccount, session, tx)?;
+            export_psbt(&psbt, out.as_deref())?;
+        }
+        Command::FinalizePsbt { psbt } => {
+            let psbt = airgap::import_psbt(psbt)?;
+            airgap::check_signed_psbt(&psbt, signer.get_fingerprint()?)?;
+            sign_and_broadcast(&subaccount, psbt)?;
+        }
+        Command::SignPsbt { .. }
+        | Command::Daemon { .. }
+        | Command::Subaccounts { .. }
+        | Command::ExportXpubs { .. }
+        | Command::ExportDescriptors { .. }
+        | Command::SignMessage { .. } => {
+            unreachable!()
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use std::error::Error;
+
+use bdk::bitcoin;
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::{hash160, Hash};
+use bitcoin::secp256k1::recovery::{RecoverableSignature, RecoveryId};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::DerivationPath;
+use bitcoin::{Address, Network, PublicKey};
+
+use crate::ga::{get_login_path, get_sign_message_hash};
+use crate::signer::UserSigner;
+
+/// Sign `message` with the key at `path`, returning the signature in the base64 format used by
+/// Bitcoin Core and most wallets: a header byte with the recovery id followed by the compact
+/// signature
+pub fn sign_message(
+    signer: &dyn UserSigner,
+    path: &DerivationPath,
+    message: &str,
+) -> Result<String, Box<dyn Error>> {
+    let secp = Secp256k1::verification_only();
+    let hash = get_sign_message_hash(message);
+
+    let public_key = signer.get_xpub(path)?.public_key;
+    let signature = signer.sign_message(path, message)?.serialize_compact();
+
+    // Signers only return the signature, find the recovery id that gives back our key
+    for id in 0..4 {
+        let recoverable =
+            RecoverableSignature::from_compact(&signature, RecoveryId::from_i32(id)?)?;
+        if secp.recover(&hash, &recoverable).ok() == Some(public_key.key) {
+            // 27 + recovery id, + 4 for compressed keys
+            let mut encoded = vec![31 + id as u8];
+            encoded.extend_from_slice(&signature);
+
+            return Ok(base64::encode(&encoded));
+        }
+    }
+
+    Err("the signer returned an invalid signature".into())
+}
+
+/// Sign `message` with the key used to login, whose address is returned with the signature
+pub fn sign_login_message(
+    signer: &dyn UserSigner,
+    network: Network,
+    message: &str,
+) -> Result<(Address, String), Box<dyn Error>> {
+    let path = get_login_path();
+    let address = Address::p2pkh(&signer.get_xpub(&path)?.public_key, network);
+
+    Ok((address, sign_message(signer, &path, message)?))
+}
+
+/// Public key that produced the base64 `signature` of `message`
+pub fn recover_public_key(signature: &str, message: &str) -> Result<PublicKey, Box<dyn Error>> {
+    let signature = base64::decode(signature.trim())?;
+    if signature.len() != 65 {
+        return Err("invalid signature length".into());
+    }
+
+    // Headers from 27 to 42 encode the key and address type, which are checked against the
+    // address anyway
+    let header = signature[0];
+    if header < 27 || header > 42 {
+        return Err("invalid signature header".into());
+    }
+    let compressed = header >= 31;
+    let id = RecoveryId::from_i32(((header - 27) % 4) as i32)?;
+
+    let recoverable = RecoverableSignature::from_compact(&signature[1..], id)?;
+    let key =
+        Secp256k1::verification_only().recover(&get_sign_message_hash(message), &recoverable)?;
+
+    Ok(PublicKey { compressed, key })
+}
+
+/// Check `signature` against a single-sig `address` (p2pkh, p2wpkh or p2sh-p2wpkh)
+///
+/// Multisig addresses of a subaccount can be checked with [`Subaccount::verify_message`].
+///
+/// [`Subaccount::verify_message`]: crate::subaccount::Subaccount::verify_message
+pub fn verify_message(
+    address: &Address,
+    signature: &str,
+    message: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let public_key = recover_public_key(signature, message)?;
+
+    let mut candidates = vec![Address::p2pkh(&public_key, address.network).script_pubkey()];
+    // Segwit only allows compressed keys
+    if public_key.compressed {
+        let witness_program = hash160::Hash::hash(&public_key.to_bytes());
+        let p2wpkh = Builder::new()
+            .push_int(0)
+            .push_slice(&witness_program[..])
+            .into_script();
+        let p2sh_p2wpkh = Builder::new()
+            .push_opcode(opcodes::all::OP_HASH160)
+            .push_slice(&hash160::Hash::hash(p2wpkh.as_bytes())[..])
+            .push_opcode(opcodes::all::OP_EQUAL)
+            .into_script();
+
+        candidates.push(p2wpkh);
+        candidates.push(p2sh_p2wpkh);
+    }
+
+    Ok(candidates.contains(&address.script_pubkey()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use bitcoin::util::bip32::ExtendedPrivKey;
+
+    use crate::signer::SoftwareSigner;
+
+    // From the `signmessage` functional test of Bitcoin Core
+    const CORE_ADDRESS: &str = "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB";
+    const CORE_SIGNATURE: &str =
+        "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+    const CORE_MESSAGE: &str = "This is just a test message";
+
+    fn with_header(signature: &str, header: u8) -> String {
+        let mut signature = base64::decode(signature).unwrap();
+        signature[0] = header;
+        base64::encode(&signature)
+    }
+
+    #[test]
+    fn test_verify_core_signature() {
+        let address = Address::from_str(CORE_ADDRESS).unwrap();
+
+        assert!(verify_message(&address, CORE_SIGNATURE, CORE_MESSAGE).unwrap());
+        assert!(!verify_message(&address, CORE_SIGNATURE, "Another message").unwrap());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let xprv = ExtendedPrivKey::new_master(Network::Testnet, &[42; 32]).unwrap();
+        let signer = SoftwareSigner::new(xprv);
+
+        let (address, signature) =
+            sign_login_message(&signer, Network::Testnet, CORE_MESSAGE).unwrap();
+        assert!(verify_message(&address, &signature, CORE_MESSAGE).unwrap());
+
+        let other = Address::from_str(CORE_ADDRESS).unwrap();
+        assert!(!verify_message(&other, &signature, CORE_MESSAGE).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        for header in &[0, 26, 43, 255] {
+            let signature = with_header(CORE_SIGNATURE, *header);
+            assert!(recover_public_key(&signature, CORE_MESSAGE).is_err());
+        }
+
+        // Same key and recovery id as an uncompressed signature
+        let signature = with_header(CORE_SIGNATURE, 28);
+        assert!(
+            !recover_public_key(&signature, CORE_MESSAGE)
+                .unwrap()
+                .compressed
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+/// Local information about a subaccount, never sent to the server
+///
+/// Stored in the [`LocalStore`] of the subaccount, so it's encrypted along with the wallet
+/// database.
+///
+/// [`LocalStore`]: crate::database::LocalStore
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SubaccountMetadata {
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Colour used to display the subaccount, as `#rrggbb`
+    #[serde(default)]
+    pub color: Option<String>,
+    /// What the subaccount is used for, like `savings`
+    #[serde(default)]
+    pub purpose: Option<String>,
+}
+
+impl SubaccountMetadata {
+    pub(crate) fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(color) = &self.color {
+            let valid = color.len() == 7
+                && color.starts_with('#')
+                && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                return Err(format!("invalid colour `{}`, expected `#rrggbb`", color).into());
+            }
+        }
+        if self.labels.iter().any(|l| l.trim().is_empty()) {
+            return Err("labels can't be empty".into());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use std::error::Error;
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use url::Url;
+
+type ProxyError = Box<dyn Error + Send + Sync>;
+
+/// Open a connection to `host:port` through the SOCKS5 proxy at `proxy`
+///
+/// The host name is sent to the proxy instead of being resolved locally, so that `.onion`
+/// addresses can be reached through Tor.
+pub async fn socks5_connect(proxy: &str, host: &str, port: u16) -> Result<TcpStream, ProxyError> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    // Version 5, one method: no authentication
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut response = [0u8; 2];
+    stream.read_exact(&mut response).await?;
+    if response != [0x05, 0x00] {
+        return Err("the SOCKS5 proxy requires an unsupported authentication method".into());
+    }
+
+    if host.len() > 255 {
+        return Err("host name too long".into());
+    }
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut response = [0u8; 4];
+    stream.read_exact(&mut response).await?;
+    if response[1] != 0x00 {
+        return Err(format!(
+            "the SOCKS5 proxy refused the connection: {:#04x}",
+            response[1]
+        )
+        .into());
+    }
+
+    // Skip the address the proxy bound to
+    let address_len = match response[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        _ => return Err("invalid address type in the SOCKS5 response".into()),
+    };
+    let mut bound_address = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound_address).await?;
+
+    Ok(stream)
+}
+
+/// Requests heads bigger than this are rejected, the websocket handshake is a few hundred bytes
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Expose a websocket `endpoint` on a local plaintext port, tunnelling it through the SOCKS5
+/// proxy. TLS is handled here with the real host name, so certificates are still verified, and
+/// the `Host` header of the handshake is rewritten to the real host.
+///
+/// Returns the local `ws://` url to connect to. Every connection to it opens a new tunnel, so
+/// the same url can be used to reconnect.
+pub async fn spawn_websocket_forwarder(proxy: &str, endpoint: &str) -> Result<String, ProxyError> {
+    let endpoint = Url::parse(endpoint)?;
+    let tls = match endpoint.scheme() {
+        "wss" => true,
+        "ws" => false,
+        scheme => return Err(format!("unsupported scheme `{}`", scheme).into()),
+    };
+    let host = endpoint
+        .host_str()
+        .ok_or("missing host in the endpoint")?
+        .to_string();
+    let port = endpoint
+        .port_or_known_default()
+        .ok_or("missing port in the endpoint")?;
+    // The port is only part of the header when it's not the default one
+    let host_header = match endpoint.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.clone(),
+    };
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_url = format!("ws://{}{}", listener.local_addr()?, endpoint.path());
+
+    let proxy = proxy.to_string();
+    tokio::spawn(async move {
+        loop {
+            let inbound = match listener.accept().await {
+                Ok((inbound, _)) => inbound,
+                Err(e) => {
+                    log::warn!("unable to accept a connection for `{}`: {:?}", host, e);
+                    continue;
+                }
+            };
+
+            let (proxy, host, host_header) = (proxy.clone(), host.clone(), host_header.clone());
+            tokio::spawn(async move {
+                if let Err(e) = forward(inbound, &proxy, &host, &host_header, port, tls).await {
+                    log::warn!("proxied connection to `{}` closed: {:?}", host, e);
+                }
+            });
+        }
+    });
+
+    Ok(local_url)
+}
+
+async fn forward(
+    mut inbound: TcpStream,
+    proxy: &str,
+    host: &str,
+    host_header: &str,
+    port: u16,
+    tls: bool,
+) -> Result<(), ProxyError> {
+    let head = read_request_head(&mut inbound, host_header).await?;
+    let mut outbound = socks5_connect(proxy, host, port).await?;
+
+    if tls {
+        let connector = tokio_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        let mut outbound = connector.connect(host, outbound).await?;
+        outbound.write_all(&head).await?;
+
+        Ok(pipe(inbound, outbound).await?)
+    } else {
+        outbound.write_all(&head).await?;
+
+        Ok(pipe(inbound, outbound).await?)
+    }
+}
+
+/// Read the head of the HTTP request sent by the websocket client, replacing its `Host` header
+/// that points to the local forwarder. Bytes read past the head are returned with it.
+async fn read_request_head(
+    inbound: &mut TcpStream,
+    host_header: &str,
+) -> Result<Vec<u8>, ProxyError> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let head_len = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err("request head too big".into());
+        }
+
+        let read = inbound.read(&mut chunk).await?;
+        if read == 0 {
+            return Err("connection closed before the handshake".into());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = std::str::from_utf8(&buffer[..head_len])?
+        .split("\r\n")
+        .map(
+            |line| match line.to_ascii_lowercase().starts_with("host:") {
+                true => format!("Host: {}", host_header),
+                false => line.to_string(),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("\r\n");
+
+    let mut head = head.into_bytes();
+    head.extend_from_slice(&buffer[head_len..]);
+
+    Ok(head)
+}
+
+async fn pipe<A, B>(a: A, b: B) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+
+    tokio::try_join!(
+        tokio::io::copy(&mut a_read, &mut b_write),
+        tokio::io::copy(&mut b_read, &mut a_write),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::oneshot;
+
+    /// Minimal SOCKS5 server that accepts `connections` connections, reporting the requested
+    /// destination and the first request head it receives on each of them
+    async fn spawn_socks5_server(
+        connections: usize,
+    ) -> (String, oneshot::Receiver<Vec<(String, u16, String)>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [0x05, 0x01, 0x00]);
+                stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+                let mut request = [0u8; 5];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x03]);
+                let mut host = vec![0u8; request[4] as usize];
+                stream.read_exact(&mut host).await.unwrap();
+                let port = stream.read_u16().await.unwrap();
+                stream
+                    .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+                    .await
+                    .unwrap();
+
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                stream.write_all(b"HTTP/1.1 101 OK\r\n\r\n").await.unwrap();
+
+                requests.push((
+                    String::from_utf8(host).unwrap(),
+                    port,
+                    String::from_utf8(head).unwrap(),
+                ));
+            }
+
+            sender.send(requests).unwrap();
+        });
+
+        (address, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect() {
+        let (proxy, requests) = spawn_socks5_server(1).await;
+
+        let mut stream = socks5_connect(&proxy, "example.onion", 1234).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = [0u8; 19];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 101 OK\r\n\r\n");
+
+        let requests = requests.await.unwrap();
+        assert_eq!(requests[0].0, "example.onion");
+        assert_eq!(requests[0].1, 1234);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_forwarder() {
+        let (proxy, requests) = spawn_socks5_server(2).await;
+
+        let local_url = spawn_websocket_forwarder(&proxy, "ws://green.example:8080/v2/ws/")
+            .await
+            .unwrap();
+        let local_url = Url::parse(&local_url).unwrap();
+        assert_eq!(local_url.path(), "/v2/ws/");
+        let local_address = format!(
+            "{}:{}",
+            local_url.host_str().unwrap(),
+            local_url.port().unwrap()
+        );
+
+        // Reconnections go through the same forwarder
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(&local_address).await.unwrap();
+            let request = format!(
+                "GET /v2/ws/ HTTP/1.1\r\nhost: {}\r\nUpgrade: websocket\r\n\r\n",
+                local_address
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = [0u8; 19];
+            stream.read_exact(&mut response).await.unwrap();
+        }
+
+        for (host, port, head) in requests.await.unwrap() {
+            assert_eq!(host, "green.example");
+            assert_eq!(port, 8080);
+            assert_eq!(
+                head,
+                "GET /v2/ws/ HTTP/1.1\r\nHost: green.example:8080\r\nUpgrade: websocket\r\n\r\n"
+            );
+        }
+    }
+}
@@ -0,0 +1,686 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use bdk::bitcoin;
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{Address, Network, OutPoint, Transaction, Txid};
+
+use serde_json::{json, Value};
+
+use bdk::blockchain::{Blockchain, Capability, Progress};
+use bdk::database::BatchDatabase;
+use bdk::{Error, FeeRate, ScriptType, TransactionDetails, UTXO};
+
+/// Wallet transactions are listed in pages of this size
+const PAGE_SIZE: usize = 1000;
+
+/// Credentials of the bitcoind RPC interface
+#[derive(Debug, Clone)]
+pub enum RpcAuth {
+    UserPass {
+        user: String,
+        password: String,
+    },
+    /// The `.cookie` file written by bitcoind in its data directory
+    Cookie(PathBuf),
+}
+
+/// Connection to a bitcoind node
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    /// Url of the RPC interface, like `http://127.0.0.1:18332`
+    pub url: String,
+    pub auth: RpcAuth,
+    /// Watch-only wallet of the node where the scripts of the subaccounts are imported, it's
+    /// created when missing
+    pub wallet_name: String,
+    /// Unix timestamp from which the node rescans the chain for the imported descriptors, usually
+    /// the creation time of the Green wallet
+    pub rescan_since: u64,
+    /// Public `sh(wsh(multi))` descriptors of the subaccounts, with their checksum
+    pub descriptors: Vec<String>,
+    pub network: Network,
+}
+
+/// Backend that syncs through a watch-only wallet of a bitcoind node, so no third party learns
+/// the addresses of the wallet
+///
+/// The descriptors of the subaccounts are imported in a descriptor wallet of the node, up to the
+/// last script cached in the database, then the transactions and unspent outputs it reports are
+/// copied back to the database. Requires Bitcoin Core 0.21 or newer.
+#[derive(Debug)]
+pub struct RpcBlockchain {
+    config: RpcConfig,
+}
+
+impl RpcBlockchain {
+    pub fn new(config: RpcConfig) -> Self {
+        RpcBlockchain { config }
+    }
+
+    fn call(&self, path: &str, method: &str, params: Value) -> Result<Value, Error> {
+        let credentials = match &self.config.auth {
+            RpcAuth::UserPass { user, password } => format!("{}:{}", user, password),
+            RpcAuth::Cookie(path) => fs::read_to_string(path)
+                .map_err(|e| Error::Generic(format!("unable to read the RPC cookie: {}", e)))?
+                .trim()
+                .to_string(),
+        };
+
+        let url = format!("{}{}", self.config.url.trim_end_matches('/'), path);
+        // bitcoind answers errors with a non-2xx status and the error in the body
+        let response = ureq::post(&url)
+            .set(
+                "Authorization",
+                &format!("Basic {}", base64::encode(&credentials)),
+            )
+            .send_json(json!({
+                "jsonrpc": "1.0",
+                "id": "neerg",
+                "method": method,
+                "params": params,
+            }));
+        if let Some(e) = response.synthetic_error() {
+            return Err(Error::Generic(format!("bitcoind RPC unreachable: {}", e)));
+        }
+
+        let mut response = response
+            .into_json()
+            .map_err(|e| Error::Generic(format!("invalid bitcoind RPC response: {}", e)))?;
+        match response["error"].take() {
+            Value::Null => Ok(response["result"].take()),
+            error => Err(Error::Generic(format!(
+                "bitcoind RPC `{}` failed: {}",
+                method, error
+            ))),
+        }
+    }
+
+    fn call_node(&self, method: &str, params: Value) -> Result<Value, Error> {
+        self.call("/", method, params)
+    }
+
+    fn call_wallet(&self, method: &str, params: Value) -> Result<Value, Error> {
+        self.call(
+            &format!("/wallet/{}", self.config.wallet_name),
+            method,
+            params,
+        )
+    }
+
+    /// Load the watch-only wallet, creating it the first time
+    fn load_wallet(&self) -> Result<(), Error> {
+        let loaded = self.call_node("listwallets", json!([]))?;
+        if let Some(loaded) = loaded.as_array() {
+            if loaded.iter().any(|name| name == &self.config.wallet_name) {
+                return Ok(());
+            }
+        }
+
+        if self
+            .call_node("loadwallet", json!([self.config.wallet_name]))
+            .is_err()
+        {
+            // No private keys, so the node can't be used to spend
+            self.call_node(
+                "createwallet",
+                json!({
+                    "wallet_name": self.config.wallet_name,
+                    "disable_private_keys": true,
+                    "blank": true,
+                    "descriptors": true,
+                }),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Import the descriptors in the node wallet if it doesn't watch the last script cached in
+    /// the database yet
+    fn import_descriptors<D: BatchDatabase>(&self, database: &D) -> Result<(), Error> {
+        let last_index = match database.iter_script_pubkeys(None)?.len() {
+            0 => return Ok(()),
+            cached => cached as u32 - 1,
+        };
+        let last_script = database
+            .get_script_pubkey_from_path(ScriptType::External, last_index)?
+            .ok_or_else(|| Error::Generic(format!("missing script at index {}", last_index)))?;
+        let last_address = Address::from_script(&last_script, self.config.network)
+            .ok_or_else(|| Error::Generic("the cached script has no address".into()))?;
+
+        let info = self.call_wallet("getaddressinfo", json!([last_address.to_string()]))?;
+        if info["ismine"] == true {
+            return Ok(());
+        }
+
+        // Always from the creation of the wallet, a script cached now may have been used by
+        // another client long ago
+        let requests = self
+            .config
+            .descriptors
+            .iter()
+            .map(|desc| {
+                json!({
+                    "desc": desc,
+                    "timestamp": self.config.rescan_since,
+                    "active": false,
+                    "range": [0, last_index],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let results = self.call_wallet("importdescriptors", json!([requests]))?;
+        for result in results.as_array().into_iter().flatten() {
+            if result["success"] != true {
+                return Err(Error::Generic(format!(
+                    "unable to import a descriptor in bitcoind: {}",
+                    result["error"]
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn list_wallet_txids(&self) -> Result<Vec<Txid>, Error> {
+        let mut txids = Vec::new();
+        let mut seen = HashSet::new();
+        for skip in (0..).step_by(PAGE_SIZE) {
+            let page = self.call_wallet("listtransactions", json!(["*", PAGE_SIZE, skip, true]))?;
+            let page = page.as_array().cloned().unwrap_or_default();
+
+            for entry in &page {
+                let txid = parse_txid(&entry["txid"])?;
+                if seen.insert(txid) {
+                    txids.push(txid);
+                }
+            }
+            if page.len() < PAGE_SIZE {
+                break;
+            }
+        }
+
+        Ok(txids)
+    }
+
+    /// Copy the transactions of the node wallet that touch our scripts to the database
+    fn sync_transactions<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        tip: u32,
+    ) -> Result<HashMap<Txid, Transaction>, Error> {
+        let mut txs = Vec::new();
+        for txid in self.list_wallet_txids()? {
+            let entry = self.call_wallet("gettransaction", json!([txid.to_string(), true]))?;
+            let tx: Transaction = parse_hex(&entry["hex"])?;
+
+            let confirmations = entry["confirmations"].as_i64().unwrap_or(0);
+            let height = match confirmations {
+                c if c > 0 => Some((tip + 1).saturating_sub(c as u32)),
+                _ => None,
+            };
+            let timestamp = entry["blocktime"]
+                .as_u64()
+                .or_else(|| entry["time"].as_u64())
+                .unwrap_or(0);
+
+            // The previous outputs are needed to know what we spent
+            database.set_raw_tx(&tx)?;
+            txs.push((tx, height, timestamp));
+        }
+
+        let mut last_index = [(ScriptType::External, None), (ScriptType::Internal, None)];
+        let mut ours = HashMap::new();
+        for (tx, height, timestamp) in txs {
+            let mut received = 0;
+            for output in &tx.output {
+                if let Some((script_type, index)) =
+                    database.get_path_from_script_pubkey(&output.script_pubkey)?
+                {
+                    received += output.value;
+
+                    for (last_type, last) in last_index.iter_mut() {
+                        if *last_type == script_type {
+                            *last = std::cmp::max(*last, Some(index));
+                        }
+                    }
+                }
+            }
+
+            let (mut sent, mut inputs_value, mut all_inputs_known) = (0, 0, true);
+            for input in &tx.input {
+                let previous = database
+                    .get_raw_tx(&input.previous_output.txid)?
+                    .and_then(|prev| {
+                        prev.output
+                            .get(input.previous_output.vout as usize)
+                            .cloned()
+                    });
+                match previous {
+                    Some(previous) => {
+                        inputs_value += previous.value;
+                        if database
+                            .get_path_from_script_pubkey(&previous.script_pubkey)?
+                            .is_some()
+                        {
+                            sent += previous.value;
+                        }
+                    }
+                    None => all_inputs_known = false,
+                }
+            }
+
+            // Other subaccounts may share the node wallet
+            if received == 0 && sent == 0 {
+                continue;
+            }
+
+            let outputs_value = tx.output.iter().map(|output| output.value).sum::<u64>();
+            let fees = match all_inputs_known {
+                true => inputs_value.saturating_sub(outputs_value),
+                false => 0,
+            };
+
+            database.set_tx(&TransactionDetails {
+                transaction: Some(tx.clone()),
+                txid: tx.txid(),
+                timestamp,
+                received,
+                sent,
+                fees,
+                height,
+            })?;
+            ours.insert(tx.txid(), tx);
+        }
+
+        for (script_type, index) in last_index.iter() {
+            if let Some(index) = *index {
+                if database.get_last_index(*script_type)?.unwrap_or(0) < index {
+                    database.set_last_index(*script_type, index)?;
+                }
+            }
+        }
+
+        Ok(ours)
+    }
+
+    fn sync_utxos<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        txs: &HashMap<Txid, Transaction>,
+    ) -> Result<(), Error> {
+        let unspent = self.call_wallet("listunspent", json!([0, 9_999_999, [], true]))?;
+
+        let mut utxos = Vec::new();
+        for entry in unspent.as_array().into_iter().flatten() {
+            let outpoint = OutPoint::new(
+                parse_txid(&entry["txid"])?,
+                entry["vout"].as_u64().unwrap_or(0) as u32,
+            );
+            let txout = match txs.get(&outpoint.txid) {
+                Some(tx) => tx.output.get(outpoint.vout as usize).cloned(),
+                None => None,
+            };
+            let txout = match txout {
+                Some(txout) => txout,
+                None => continue,
+            };
+
+            if let Some((script_type, _)) =
+                database.get_path_from_script_pubkey(&txout.script_pubkey)?
+            {
+                utxos.push(UTXO {
+                    outpoint,
+                    txout,
+                    is_internal: script_type == ScriptType::Internal,
+                });
+            }
+        }
+
+        let unspent = utxos
+            .iter()
+            .map(|utxo| utxo.outpoint)
+            .collect::<HashSet<_>>();
+        for utxo in database.iter_utxos()? {
+            if !unspent.contains(&utxo.outpoint) {
+                database.del_utxo(&utxo.outpoint)?;
+            }
+        }
+        for utxo in utxos {
+            database.set_utxo(&utxo)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync_database<D: BatchDatabase, P: 'static + Progress>(
+        &self,
+        database: &mut D,
+        progress_update: P,
+    ) -> Result<(), Error> {
+        self.load_wallet()?;
+        self.import_descriptors(database)?;
+        progress_update.update(50.0, Some("descriptors imported".into()))?;
+
+        let tip = self.get_height()?;
+        let txs = self.sync_transactions(database, tip)?;
+        self.sync_utxos(database, &txs)?;
+
+        progress_update.update(100.0, None)
+    }
+}
+
+impl Blockchain for RpcBlockchain {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        vec![Capability::FullHistory].into_iter().collect()
+    }
+
+    fn setup<D: BatchDatabase, P: 'static + Progress>(
+        &self,
+        _stop_gap: Option<usize>,
+        database: &mut D,
+        progress_update: P,
+    ) -> Result<(), Error> {
+        self.sync_database(database, progress_update)
+    }
+
+    fn sync<D: BatchDatabase, P: 'static + Progress>(
+        &self,
+        _stop_gap: Option<usize>,
+        database: &mut D,
+        progress_update: P,
+    ) -> Result<(), Error> {
+        self.sync_database(database, progress_update)
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        // Without `txindex` the node only knows the transactions of its wallets and mempool
+        let hex = match self.call_wallet("gettransaction", json!([txid.to_string(), true])) {
+            Ok(entry) => entry["hex"].clone(),
+            Err(_) => match self.call_node("getrawtransaction", json!([txid.to_string()])) {
+                Ok(hex) => hex,
+                Err(_) => return Ok(None),
+            },
+        };
+
+        Ok(Some(parse_hex(&hex)?))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        self.call_node("sendrawtransaction", json!([serialize(tx).to_hex()]))?;
+
+        Ok(())
+    }
+
+    fn get_height(&self) -> Result<u32, Error> {
+        let height = self.call_node("getblockcount", json!([]))?;
+
+        height
+            .as_u64()
+            .map(|height| height as u32)
+            .ok_or_else(|| Error::Generic("invalid block count".into()))
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        let estimate = self.call_node("estimatesmartfee", json!([target]))?;
+
+        // In BTC/kvB, missing when the node doesn't have enough data
+        let btc_per_kvb = estimate["feerate"].as_f64().ok_or_else(|| {
+            Error::Generic(format!(
+                "bitcoind has no fee estimate for {} blocks",
+                target
+            ))
+        })?;
+        Ok(FeeRate::from_sat_per_vb((btc_per_kvb * 100_000.0) as f32))
+    }
+}
+
+fn parse_txid(value: &Value) -> Result<Txid, Error> {
+    value
+        .as_str()
+        .and_then(|txid| Txid::from_hex(txid).ok())
+        .ok_or_else(|| Error::Generic(format!("invalid txid `{}`", value)))
+}
+
+fn parse_hex(value: &Value) -> Result<Transaction, Error> {
+    value
+        .as_str()
+        .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+        .and_then(|bytes| deserialize(&bytes).ok())
+        .ok_or_else(|| Error::Generic("invalid raw transaction from bitcoind".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use bdk::database::{BatchOperations, MemoryDatabase};
+
+    use bitcoin::blockdata::script::Builder;
+
+    // The fake node doesn't parse the descriptors
+    const DESCRIPTOR: &str = "sh(wsh(multi(2,[00000000]service,[ffffffff/3/1]user/1/*)))";
+    const BIRTHDAY: u64 = 1_600_000_000;
+
+    type Calls = Arc<Mutex<Vec<(String, String, Value)>>>;
+
+    /// Fake bitcoind answering every RPC with `respond`, the wallet path, method and params of
+    /// each call are recorded
+    fn spawn_bitcoind<F>(respond: F) -> (String, Calls)
+    where
+        F: Fn(&str, &Value) -> Result<Value, Value> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Calls::default();
+
+        let recorded = Arc::clone(&calls);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap().to_string();
+
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let mut parts = header.splitn(2, ':');
+                    if parts.next().unwrap().eq_ignore_ascii_case("content-length") {
+                        content_length = parts.next().unwrap().trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                stream.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+
+                let method = request["method"].as_str().unwrap().to_string();
+                let response = match respond(&method, &request["params"]) {
+                    Ok(result) => (200, json!({ "result": result, "error": null })),
+                    Err(error) => (500, json!({ "result": null, "error": error })),
+                };
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((path, method, request["params"].clone()));
+
+                let body = response.1.to_string();
+                write!(
+                    stream.get_mut(),
+                    "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.0,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        (url, calls)
+    }
+
+    fn get_blockchain(url: String) -> RpcBlockchain {
+        RpcBlockchain::new(RpcConfig {
+            url,
+            auth: RpcAuth::UserPass {
+                user: "user".into(),
+                password: "password".into(),
+            },
+            wallet_name: "neerg".into(),
+            rescan_since: BIRTHDAY,
+            descriptors: vec![DESCRIPTOR.into()],
+            network: Network::Regtest,
+        })
+    }
+
+    fn get_database(cached: u32) -> MemoryDatabase {
+        let mut database = MemoryDatabase::new();
+        for index in 0..cached {
+            let script = Builder::new()
+                .push_int(index as i64)
+                .into_script()
+                .to_p2sh();
+            database
+                .set_script_pubkey(&script, ScriptType::External, index)
+                .unwrap();
+        }
+
+        database
+    }
+
+    fn get_methods(calls: &Calls) -> Vec<String> {
+        calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, method, _)| method.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_setup_imports_descriptors_from_birthday() {
+        let (url, calls) = spawn_bitcoind(|method, _| match method {
+            "listwallets" => Ok(json!([])),
+            "loadwallet" => Err(json!({ "code": -18, "message": "Wallet file not found" })),
+            "createwallet" => Ok(json!({ "name": "neerg", "warning": "" })),
+            "getaddressinfo" => Ok(json!({ "ismine": false, "solvable": false })),
+            "importdescriptors" => Ok(json!([{ "success": true }])),
+            "getblockcount" => Ok(json!(100)),
+            "listtransactions" | "listunspent" => Ok(json!([])),
+            method => panic!("unexpected call to `{}`", method),
+        });
+
+        let mut database = get_database(20);
+        get_blockchain(url)
+            .setup(None, &mut database, bdk::blockchain::noop_progress())
+            .unwrap();
+
+        assert_eq!(
+            get_methods(&calls),
+            vec![
+                "listwallets",
+                "loadwallet",
+                "createwallet",
+                "getaddressinfo",
+                "importdescriptors",
+                "getblockcount",
+                "listtransactions",
+                "listunspent"
+            ]
+        );
+
+        let calls = calls.lock().unwrap();
+        let (_, _, params) = &calls[2];
+        assert_eq!(params["descriptors"], true);
+        assert_eq!(params["disable_private_keys"], true);
+
+        let (path, _, params) = &calls[4];
+        assert_eq!(path, "/wallet/neerg");
+        assert_eq!(
+            params,
+            &json!([[{
+                "desc": DESCRIPTOR,
+                "timestamp": BIRTHDAY,
+                "active": false,
+                "range": [0, 19],
+            }]])
+        );
+    }
+
+    #[test]
+    fn test_sync_imports_new_scripts_from_birthday() {
+        // The node already watches the first scripts, but not the ones cached since then
+        let (url, calls) = spawn_bitcoind(|method, params| match method {
+            "listwallets" => Ok(json!(["neerg"])),
+            "getaddressinfo" => {
+                let ours = Address::from_script(
+                    &Builder::new().push_int(9).into_script().to_p2sh(),
+                    Network::Regtest,
+                )
+                .unwrap();
+                Ok(json!({ "ismine": params[0] == ours.to_string() }))
+            }
+            "importdescriptors" => Ok(json!([{ "success": true }])),
+            "getblockcount" => Ok(json!(100)),
+            "listtransactions" | "listunspent" => Ok(json!([])),
+            method => panic!("unexpected call to `{}`", method),
+        });
+        let blockchain = get_blockchain(url);
+
+        blockchain
+            .sync(
+                None,
+                &mut get_database(10),
+                bdk::blockchain::noop_progress(),
+            )
+            .unwrap();
+        assert!(!get_methods(&calls).contains(&"importdescriptors".to_string()));
+
+        blockchain
+            .sync(
+                None,
+                &mut get_database(30),
+                bdk::blockchain::noop_progress(),
+            )
+            .unwrap();
+        let calls = calls.lock().unwrap();
+        let (_, _, params) = calls
+            .iter()
+            .find(|(_, method, _)| method == "importdescriptors")
+            .unwrap();
+        assert_eq!(params[0][0]["timestamp"], BIRTHDAY);
+        assert_eq!(params[0][0]["range"], json!([0, 29]));
+    }
+
+    #[test]
+    fn test_failed_import_is_reported() {
+        let (url, _) = spawn_bitcoind(|method, _| match method {
+            "listwallets" => Ok(json!(["neerg"])),
+            "getaddressinfo" => Ok(json!({ "ismine": false })),
+            "importdescriptors" => Ok(json!([{
+                "success": false,
+                "error": { "code": -5, "message": "Invalid descriptor" },
+            }])),
+            method => panic!("unexpected call to `{}`", method),
+        });
+
+        let err = get_blockchain(url)
+            .sync(None, &mut get_database(1), bdk::blockchain::noop_progress())
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid descriptor"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use serde::Deserialize;
+
+use bdk::bitcoin;
+use bdk::miniscript::descriptor::DescriptorXKey;
+use bdk::wallet::signer::{Signer, SignerError};
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::secp256k1::{Secp256k1, Signature};
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+
+use crate::ga::get_sign_message_hash;
+
+/// Holder of the user half of the 2-of-2 multisig
+pub trait UserSigner: fmt::Debug + Send + Sync {
+    fn get_xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, Box<dyn Error>>;
+    /// Sign `msg` as a "Bitcoin Signed Message" with the key at `path`
+    fn sign_message(&self, path: &DerivationPath, msg: &str) -> Result<Signature, Box<dyn Error>>;
+    /// Add a signature to every input that has one of our keys in its `hd_keypaths`
+    fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<(), Box<dyn Error>>;
+
+    fn get_fingerprint(&self) -> Result<Fingerprint, Box<dyn Error>> {
+        Ok(self.get_xpub(&vec![].into())?.fingerprint())
+    }
+}
+
+#[derive(Debug)]
+pub struct SoftwareSigner {
+    xprv: ExtendedPrivKey,
+}
+
+impl SoftwareSigner {
+    pub fn new(xprv: ExtendedPrivKey) -> Self {
+        SoftwareSigner { xprv }
+    }
+}
+
+impl UserSigner for SoftwareSigner {
+    fn get_xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, Box<dyn Error>> {
+        let secp = Secp256k1::signing_only();
+        let derived = self.xprv.derive_priv(&secp, path)?;
+
+        Ok(ExtendedPubKey::from_private(&secp, &derived))
+    }
+
+    fn sign_message(&self, path: &DerivationPath, msg: &str) -> Result<Signature, Box<dyn Error>> {
+        let secp = Secp256k1::signing_only();
+        let key = self.xprv.derive_priv(&secp, path)?.private_key.key;
+
+        Ok(secp.sign(&get_sign_message_hash(msg), &key))
+    }
+
+    fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<(), Box<dyn Error>> {
+        let xkey = DescriptorXKey {
+            source: None,
+            xkey: self.xprv,
+            derivation_path: vec![].into(),
+            is_wildcard: false,
+        };
+
+        for input_index in 0..psbt.inputs.len() {
+            match xkey.sign(psbt, Some(input_index)) {
+                // Inputs that don't belong to us are left untouched
+                Ok(()) | Err(SignerError::MissingHDKeypath) => continue,
+                Err(e) => return Err(format!("{:?}", e).into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalXpubResponse {
+    xpub: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalSignMessageResponse {
+    signature: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalSignTxResponse {
+    psbt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalErrorResponse {
+    error: String,
+}
+
+/// Signer that delegates to an external process with an HWI-like interface
+///
+/// The process is invoked as `<command> [args...] <operation> [params...]` and must print a
+/// single JSON object on stdout:
+///
+/// * `getxpub <path>` -> `{"xpub": "<base58 xpub>"}`
+/// * `signmessage <message> <path>` -> `{"signature": "<base64 compact signature>"}`
+/// * `signtx` -> `{"psbt": "<base64 psbt>"}`, reading the base64 PSBT from a line on stdin
+///
+/// The PSBT doesn't go in the arguments since it can be larger than the limit of a single
+/// argument, and the arguments are visible to every local user. On failure it can print `{"error": "<message>"}` instead.
+#[derive(Debug)]
+pub struct ExternalSigner {
+    command: PathBuf,
+    args: Vec<String>,
+}
+
+impl ExternalSigner {
+    pub fn new<P: Into<PathBuf>>(command: P, args: Vec<String>) -> Self {
+        ExternalSigner {
+            command: command.into(),
+            args,
+        }
+    }
+
+    /// Run the signer with `params`, writing `input` as a line on its stdin
+    fn run<T>(&self, params: &[&str], input: Option<String>) -> Result<T, Box<dyn Error>>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .args(params)
+            .stdin(match input {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Written from another thread, so that a signer that prints before reading everything
+        // can't fill the stdout pipe and block both processes
+        let writer = match (input, child.stdin.take()) {
+            (Some(input), Some(mut stdin)) => Some(thread::spawn(move || {
+                stdin.write_all(input.as_bytes())?;
+                stdin.write_all(b"\n")
+            })),
+            _ => None,
+        };
+        let output = child.wait_with_output()?;
+        // The signer may exit without reading its input, its response explains why
+        if let Some(Ok(Err(e))) = writer.map(thread::JoinHandle::join) {
+            log::debug!("unable to write to the external signer: {}", e);
+        }
+
+        if let Ok(ExternalErrorResponse { error }) = serde_json::from_slice(&output.stdout) {
+            return Err(error.into());
+        }
+        if !output.status.success() {
+            return Err(format!(
+                "external signer exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+}
+
+impl UserSigner for ExternalSigner {
+    fn get_xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, Box<dyn Error>> {
+        let response: ExternalXpubResponse = self.run(&["getxpub", &path.to_string()], None)?;
+
+        Ok(ExtendedPubKey::from_str(&response.xpub)?)
+    }
+
+    fn sign_message(&self, path: &DerivationPath, msg: &str) -> Result<Signature, Box<dyn Error>> {
+        let response: ExternalSignMessageResponse =
+            self.run(&["signmessage", msg, &path.to_string()], None)?;
+
+        parse_compact_signature(&response.signature)
+    }
+
+    fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<(), Box<dyn Error>> {
+        let response: ExternalSignTxResponse =
+            self.run(&["signtx"], Some(base64::encode(&serialize(psbt))))?;
+
+        let signed: PartiallySignedTransaction = deserialize(&base64::decode(&response.psbt)?)?;
+        psbt.merge(signed)?;
+
+        Ok(())
+    }
+}
+
+/// Adapter to use a [`UserSigner`] as a BDK [`Signer`]
+#[derive(Debug)]
+pub struct UserSignerAdapter {
+    pub signer: Arc<dyn UserSigner>,
+}
+
+impl Signer for UserSignerAdapter {
+    fn sign_whole_tx(&self) -> bool {
+        true
+    }
+
+    fn sign(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        _input_index: Option<usize>,
+    ) -> Result<(), SignerError> {
+        // `SignerError` can't carry the cause, so it's only logged
+        self.signer.sign_psbt(psbt).map_err(|e| {
+            log::error!("the user signer failed: {}", e);
+
+            SignerError::UserCanceled
+        })
+    }
+}
+
+/// Parse a base64 compact signature, with or without the recovery header byte
+pub(crate) fn parse_compact_signature(signature: &str) -> Result<Signature, Box<dyn Error>> {
+    let signature = base64::decode(signature.trim())?;
+    match signature.len() {
+        64 => Ok(Signature::from_compact(&signature)?),
+        65 => Ok(Signature::from_compact(&signature[1..])?),
+        _ => Err("invalid signature length".into()),
+    }
+}
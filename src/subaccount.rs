@@ -1,86 +1,755 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 use bdk::bitcoin;
 use bdk::sled;
 
-use bitcoin::util::bip32::ExtendedPrivKey;
-use bitcoin::Network;
+use bitcoin::util::psbt::PartiallySignedTransaction as PSBT;
+use bitcoin::{Address, Network, OutPoint, Transaction, TxOut, Txid};
 
-use sled::{Db, Tree};
+use serde::Serialize;
 
-use bdk::blockchain::ElectrumBlockchain;
+use sled::Tree;
+
+use bdk::blockchain::{Blockchain, ElectrumBlockchain};
+use bdk::database::{BatchDatabase, Database};
 use bdk::wallet::address_validator::AddressValidator;
 use bdk::wallet::signer::{Signer, SignerOrdering};
-use bdk::{ScriptType, Wallet};
+use bdk::{FeeRate, ScriptType, TransactionDetails, TxBuilder, Wallet, UTXO};
 
+use crate::batch::get_dust_limit;
+use crate::database::LocalStore;
 use crate::descriptor::GreenSubaccountDescriptor;
+use crate::fiat::FiatAmount;
 use crate::ga::*;
-use crate::twofactor::StdinResolver;
-use crate::types::TwoFactorConfigResponse;
+use crate::message;
+use crate::metadata::SubaccountMetadata;
+use crate::signer::{UserSigner, UserSignerAdapter};
+use crate::twofactor::TwoFactorResolver;
+use crate::types::{TwoFactorAction, TwoFactorConfigResponse, UtxoStatus, UtxoStatusChange};
+
+/// Key of the statuses changed only locally, in the [`LocalStore`]
+const LOCAL_UTXO_STATUS_KEY: &str = "utxo_status";
+/// Key of the [`SubaccountMetadata`], in the [`LocalStore`]
+const LOCAL_METADATA_KEY: &str = "metadata";
 
-pub struct Subaccount {
-    wallet: Wallet<Arc<ElectrumBlockchain>, Tree>,
+pub struct Subaccount<B: Blockchain = ElectrumBlockchain, D: BatchDatabase = Tree> {
+    wallet: Wallet<Arc<B>, D>,
+    session: Arc<GAClient>,
+    signer: Arc<dyn UserSigner>,
+    gait_path: Vec<u16>,
+    pointer: u16,
+    /// Also added to the wallet, kept to co-sign actions other than a plain send
+    service_signer: GASigner<dyn TwoFactorResolver>,
+    /// Statuses from the server with the local changes on top
+    utxo_status: Mutex<HashMap<OutPoint, UtxoStatus>>,
+    local: LocalStore,
 }
 
-impl Subaccount {
+/// Inputs to pass to the `TxBuilder`, see [`Subaccount::get_coin_control`]
+#[derive(Debug, Default)]
+pub struct CoinControl {
+    /// Inputs that must be spent
+    pub utxos: Vec<OutPoint>,
+    /// Inputs that must not be spent
+    pub unspendable: Vec<OutPoint>,
+}
+
+/// Transaction from the local history, with the memo stored on the server
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub details: TransactionDetails,
+    pub memo: Option<String>,
+    /// Unix time when the server first saw the transaction, unlike `details.timestamp` that
+    /// depends on when the wallet was synced
+    pub created_at: Option<u64>,
+}
+
+impl<B: Blockchain, D: BatchDatabase> Subaccount<B, D> {
     pub fn new(
-        xprv: &ExtendedPrivKey,
+        signer: &Arc<dyn UserSigner>,
         gait_path: &Vec<u16>,
         pointer: u16,
-        db: &Db,
-        client: &Arc<ElectrumBlockchain>,
+        database: D,
+        client: &Arc<B>,
         session: &Arc<GAClient>,
+        resolver: Arc<dyn TwoFactorResolver>,
         twofactor_config: TwoFactorConfigResponse,
     ) -> Result<Self, Box<dyn Error>> {
-        let tree = db.open_tree(pointer.to_string())?;
-
-        let pointer = match pointer {
-            0 => None,
-            p => Some(p),
-        };
-
-        let desc = GreenSubaccountDescriptor {
-            xprv,
-            gait_path,
-            subaccount: pointer,
-        };
+        let desc = GreenSubaccountDescriptor::from_signer(signer.as_ref(), gait_path, pointer)?;
+        let user_fingerprint = desc.user_fingerprint;
         let service_fingerprint = desc.get_service_fingerprint(Network::Testnet);
 
         // let (desc, service_fingerprint) =
         //     get_descriptor(xprv, gait_path, pointer, Network::Testnet);
 
-        let mut wallet = Wallet::new(desc, None, Network::Testnet, tree, Arc::clone(&client))?;
+        let mut wallet = Wallet::new(desc, None, Network::Testnet, database, Arc::clone(&client))?;
+
+        let user_signer = Box::new(UserSignerAdapter {
+            signer: Arc::clone(signer),
+        }) as Box<dyn Signer>;
+        wallet.add_signer(
+            ScriptType::External,
+            user_fingerprint.into(),
+            SignerOrdering(100),
+            Arc::new(user_signer),
+        );
 
-        let signer = Box::new(GASigner {
+        let service_signer = GASigner {
             session: Arc::clone(session),
             service_fingerprint,
-            resolver: Arc::new(StdinResolver),
+            resolver,
             twofactor_config,
-        }) as Box<dyn Signer>;
+        };
         wallet.add_signer(
             ScriptType::External,
             service_fingerprint.into(),
             SignerOrdering(200),
-            Arc::new(signer),
+            Arc::new(Box::new(service_signer.clone()) as Box<dyn Signer>),
         );
 
         let address_validator = Box::new(GAAddressValidator {
             session: Arc::clone(session),
-            subaccount: pointer.unwrap_or(0),
+            subaccount: pointer,
             service_fingerprint,
         }) as Box<dyn AddressValidator>;
         wallet.add_address_validator(Arc::new(address_validator));
 
-        Ok(Subaccount { wallet })
+        Ok(Subaccount {
+            wallet,
+            session: Arc::clone(session),
+            signer: Arc::clone(signer),
+            gait_path: gait_path.clone(),
+            pointer,
+            service_signer,
+            utxo_status: Mutex::new(HashMap::new()),
+            local: LocalStore::memory(),
+        })
+    }
+
+    /// Keep the local changes of the subaccount in `store`, usually a tree of the wallet database,
+    /// instead of losing them when the subaccount is closed
+    pub fn with_local_store(mut self, store: LocalStore) -> Self {
+        self.local = store;
+        self
+    }
+
+    pub fn get_pointer(&self) -> u16 {
+        self.pointer
+    }
+
+    /// Local metadata, empty when it was never set
+    pub fn get_metadata(&self) -> Result<SubaccountMetadata, Box<dyn Error>> {
+        Ok(self.local.get(LOCAL_METADATA_KEY)?.unwrap_or_default())
+    }
+
+    pub fn set_metadata(&self, metadata: SubaccountMetadata) -> Result<(), Box<dyn Error>> {
+        metadata.validate()?;
+
+        // Empty metadata is removed instead of stored
+        let value = Some(&metadata).filter(|m| **m != SubaccountMetadata::default());
+        self.local.set(LOCAL_METADATA_KEY, value)
+    }
+
+    /// Index of `address` in the wallet database, which knows every address derived so far
+    fn find_address_index(&self, address: &Address) -> Result<u32, Box<dyn Error>> {
+        match self
+            .wallet
+            .database()
+            .get_path_from_script_pubkey(&address.script_pubkey())?
+        {
+            Some((_, index)) => Ok(index),
+            None => Err(format!("{} is not an address of the subaccount", address).into()),
+        }
+    }
+
+    /// Sign `message` with the user key of `address`, to prove that we own it
+    ///
+    /// Since the address is a 2of2 multisig the signature can't be checked with the address
+    /// alone like for single-sig ones: the verifier needs the user key, which is what
+    /// [`Subaccount::verify_message`] checks.
+    pub fn sign_message(&self, address: &Address, message: &str) -> Result<String, Box<dyn Error>> {
+        let desc = GreenSubaccountDescriptor::from_signer(
+            self.signer.as_ref(),
+            &self.gait_path,
+            self.pointer,
+        )?;
+        let index = self.find_address_index(address)?;
+
+        message::sign_message(
+            self.signer.as_ref(),
+            &desc.get_user_key_path(index),
+            message,
+        )
+    }
+
+    /// Check that `signature` was made by the user key of `address`
+    pub fn verify_message(
+        &self,
+        address: &Address,
+        signature: &str,
+        message: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let desc = GreenSubaccountDescriptor::from_signer(
+            self.signer.as_ref(),
+            &self.gait_path,
+            self.pointer,
+        )?;
+        let index = self.find_address_index(address)?;
+
+        let user_key = self
+            .signer
+            .get_xpub(&desc.get_user_key_path(index))?
+            .public_key;
+        Ok(message::recover_public_key(signature, message)? == user_key)
+    }
+
+    /// Balance in the currency of the pricing source, `None` when there is no rate
+    pub fn get_fiat_balance(&self) -> Result<Option<FiatAmount>, Box<dyn Error>> {
+        let balance = self.get_balance()?;
+
+        Ok(self
+            .session
+            .get_fiat_rate()
+            .map(|rate| rate.to_fiat(balance as i64)))
+    }
+
+    pub async fn get_memos(&self) -> Result<HashMap<Txid, String>, Box<dyn Error>> {
+        Ok(self
+            .session
+            .get_all_transactions(self.pointer)
+            .await?
+            .into_iter()
+            .filter_map(|tx| match tx.memo {
+                Some(memo) if !memo.is_empty() => Some((tx.txhash, memo)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub async fn set_memo(&self, txid: &Txid, memo: &str) -> Result<(), Box<dyn Error>> {
+        self.session.set_memo(txid, memo).await
+    }
+
+    /// Local transaction history merged with the memos and times stored on the server
+    pub async fn get_history(
+        &self,
+        include_raw: bool,
+    ) -> Result<Vec<HistoryEntry>, Box<dyn Error>> {
+        let mut server_txs = self
+            .session
+            .get_all_transactions(self.pointer)
+            .await?
+            .into_iter()
+            .map(|tx| (tx.txhash, tx))
+            .collect::<HashMap<_, _>>();
+
+        Ok(self
+            .list_transactions(include_raw)?
+            .into_iter()
+            .map(|details| {
+                let server_tx = server_txs.remove(&details.txid);
+
+                HistoryEntry {
+                    memo: server_tx
+                        .as_ref()
+                        .and_then(|tx| tx.memo.clone())
+                        .filter(|memo| !memo.is_empty()),
+                    created_at: server_tx.and_then(|tx| tx.get_created_at()),
+                    details,
+                }
+            })
+            .collect())
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<TransactionDetails, Box<dyn Error>> {
+        self.list_transactions(true)?
+            .into_iter()
+            .find(|tx| &tx.txid == txid)
+            .ok_or_else(|| format!("transaction {} not found", txid).into())
+    }
+
+    fn get_local_utxo_status(&self) -> Result<HashMap<OutPoint, UtxoStatus>, Box<dyn Error>> {
+        self.local
+            .get::<HashMap<String, UtxoStatus>>(LOCAL_UTXO_STATUS_KEY)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(outpoint, status)| -> Result<_, Box<dyn Error>> {
+                Ok((OutPoint::from_str(&outpoint)?, status))
+            })
+            .collect()
+    }
+
+    fn set_local_utxo_status(
+        &self,
+        status: &HashMap<OutPoint, UtxoStatus>,
+    ) -> Result<(), Box<dyn Error>> {
+        let status = status
+            .iter()
+            .map(|(outpoint, status)| (outpoint.to_string(), *status))
+            .collect::<HashMap<_, _>>();
+
+        self.local.set(LOCAL_UTXO_STATUS_KEY, Some(&status))
+    }
+
+    /// Load the status of the unspent outputs from the server, keeping the local changes on top
+    pub async fn refresh_utxo_status(&self) -> Result<(), Box<dyn Error>> {
+        let mut status = self
+            .session
+            .get_unspent_outputs(self.pointer)
+            .await?
+            .into_iter()
+            .filter(|utxo| utxo.user_status != UtxoStatus::Default)
+            .map(|utxo| (OutPoint::new(utxo.txhash, utxo.pt_idx), utxo.user_status))
+            .collect::<HashMap<_, _>>();
+
+        // Forget the changes to outputs that have been spent since
+        let unspent = self
+            .list_unspent()?
+            .into_iter()
+            .map(|utxo| utxo.outpoint)
+            .collect::<HashSet<_>>();
+        let mut local_status = self.get_local_utxo_status()?;
+        local_status.retain(|outpoint, _| unspent.contains(outpoint));
+        self.set_local_utxo_status(&local_status)?;
+
+        status.extend(local_status);
+        *self.utxo_status.lock().unwrap() = status;
+
+        Ok(())
+    }
+
+    /// Unspent outputs with their status, outputs below the dust limit are marked as dust unless
+    /// frozen
+    pub fn list_unspent_with_status(&self) -> Result<Vec<(UTXO, UtxoStatus)>, Box<dyn Error>> {
+        Ok(with_status(
+            self.list_unspent()?,
+            &self.utxo_status.lock().unwrap(),
+        ))
+    }
+
+    /// Change the status of `outpoints`, either only locally or also on the server
+    ///
+    /// Local changes take precedence over the status on the server, until the status is changed
+    /// on the server again.
+    pub async fn set_utxo_status(
+        &self,
+        outpoints: &[OutPoint],
+        status: UtxoStatus,
+        persist: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let unspent = self
+            .list_unspent()?
+            .into_iter()
+            .map(|utxo| utxo.outpoint)
+            .collect::<HashSet<_>>();
+        if let Some(outpoint) = outpoints.iter().find(|o| !unspent.contains(o)) {
+            return Err(format!("{} is not an unspent output of the subaccount", outpoint).into());
+        }
+
+        if persist {
+            let changes = outpoints
+                .iter()
+                .map(|outpoint| UtxoStatusChange {
+                    txhash: outpoint.txid,
+                    pt_idx: outpoint.vout,
+                    user_status: status,
+                })
+                .collect();
+            self.session
+                .set_unspent_outputs_status(changes, None)
+                .await?;
+        }
+
+        let mut local_status = self.get_local_utxo_status()?;
+        for outpoint in outpoints {
+            match persist {
+                true => local_status.remove(outpoint),
+                false => local_status.insert(*outpoint, status),
+            };
+        }
+        self.set_local_utxo_status(&local_status)?;
+
+        let mut utxo_status = self.utxo_status.lock().unwrap();
+        for outpoint in outpoints {
+            utxo_status.insert(*outpoint, status);
+        }
+
+        Ok(())
+    }
+
+    /// Inputs for a new transaction: frozen and dust outputs are never spent and when `selected`
+    /// is not empty only those outputs are
+    pub fn get_coin_control(&self, selected: &[OutPoint]) -> Result<CoinControl, Box<dyn Error>> {
+        select_coins(self.list_unspent_with_status()?, selected)
+    }
+
+    /// Replace the unconfirmed transaction `txid` with one paying `fee_rate`
+    ///
+    /// The fee is taken from the change, adding inputs if needed. The replacement is checked to
+    /// still pay the original recipients the same amounts.
+    pub fn bump_fee(
+        &self,
+        txid: &Txid,
+        fee_rate: FeeRate,
+    ) -> Result<(PSBT, TransactionDetails), Box<dyn Error>> {
+        let original = self.get_transaction(txid)?;
+        if original.height.is_some() {
+            return Err("the transaction is already confirmed".into());
+        }
+
+        let (psbt, details) = self
+            .wallet
+            .bump_fee(txid, TxBuilder::new().enable_rbf().fee_rate(fee_rate))?;
+        let original_tx = original
+            .transaction
+            .as_ref()
+            .ok_or("missing raw transaction")?;
+        self.check_bump_outputs(original_tx, &psbt.global.unsigned_tx)?;
+
+        Ok((psbt, details))
+    }
+
+    fn check_bump_outputs(
+        &self,
+        original: &Transaction,
+        replacement: &Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+        let get_external = |tx: &Transaction| -> Result<Vec<TxOut>, Box<dyn Error>> {
+            let mut outputs = Vec::new();
+            for output in &tx.output {
+                if !self.is_mine(&output.script_pubkey)? {
+                    outputs.push(output.clone());
+                }
+            }
+            outputs.sort_by(|a, b| (&a.script_pubkey, a.value).cmp(&(&b.script_pubkey, b.value)));
+
+            Ok(outputs)
+        };
+
+        if get_external(original)? != get_external(replacement)? {
+            return Err("the replacement doesn't pay the same recipients as the original".into());
+        }
+
+        Ok(())
+    }
+
+    /// Sign a replacement created by [`Subaccount::bump_fee`], authorizing the fee increase with
+    /// the server
+    pub fn sign_bump(
+        &self,
+        psbt: PSBT,
+        original_txid: &Txid,
+        details: &TransactionDetails,
+    ) -> Result<(PSBT, bool), Box<dyn Error>> {
+        let original = self.get_transaction(original_txid)?;
+        let amount = details.fees.saturating_sub(original.fees);
+
+        // The wallet signers can't be told the action, so they are called directly
+        let mut psbt = psbt;
+        self.signer.sign_psbt(&mut psbt)?;
+        self.service_signer
+            .sign_with_action(&mut psbt, TwoFactorAction::BumpFee { amount })
+            .map_err(|e| format!("{:?}", e))?;
+
+        Ok(self.finalize_psbt(psbt, None)?)
+    }
+
+    /// Spend the outputs we received in the unconfirmed transaction `txid` back to us, with a fee
+    /// high enough that the two transactions together pay `fee_rate`
+    pub async fn cpfp(
+        &self,
+        txid: &Txid,
+        fee_rate: FeeRate,
+    ) -> Result<(PSBT, TransactionDetails), Box<dyn Error>> {
+        let parent = self.get_transaction(txid)?;
+        if parent.height.is_some() {
+            return Err("the transaction is already confirmed".into());
+        }
+        let parent_vsize = parent
+            .transaction
+            .as_ref()
+            .ok_or("missing raw transaction")?
+            .get_weight() as f32
+            / 4.0;
+
+        // The local wallet doesn't know the fee of incoming transactions, the server does
+        let parent_fee = match parent.fees {
+            0 => self
+                .session
+                .get_all_transactions(self.pointer)
+                .await?
+                .into_iter()
+                .find(|tx| &tx.txhash == txid)
+                .map(|tx| tx.fee)
+                .filter(|fee| *fee > 0)
+                .ok_or("the fee of the transaction is unknown")?,
+            fees => fees,
+        };
+
+        let utxos = self
+            .list_unspent()?
+            .into_iter()
+            .filter(|utxo| &utxo.outpoint.txid == txid)
+            .map(|utxo| utxo.outpoint)
+            .collect::<Vec<_>>();
+        if utxos.is_empty() {
+            return Err("the transaction has no unspent outputs for this subaccount".into());
+        }
+        let coin_control = self.get_coin_control(&utxos)?;
+
+        // Both versions of the child pay the same address
+        let to = self.get_new_address()?.script_pubkey();
+        let create_child = |fee_rate: FeeRate| {
+            let builder = TxBuilder::with_recipients(vec![(to.clone(), 0)])
+                .utxos(coin_control.utxos.clone())
+                .unspendable(coin_control.unspendable.clone())
+                .send_all()
+                .enable_rbf()
+                .fee_rate(fee_rate);
+
+            self.create_tx(builder)
+        };
+
+        // Build the child once to know its size, then again with the fee covering the parent
+        let (_, details) = create_child(fee_rate)?;
+        let child_vsize = details.fees as f32 / fee_rate.as_sat_vb();
+        let package_fee = fee_rate.as_sat_vb() * (parent_vsize + child_vsize);
+        let child_fee_rate = (package_fee - parent_fee as f32) / child_vsize;
+        if child_fee_rate <= fee_rate.as_sat_vb() {
+            return Err("the transaction already pays at least the requested fee rate".into());
+        }
+
+        Ok(create_child(FeeRate::from_sat_per_vb(child_fee_rate))?)
     }
 }
 
-impl Deref for Subaccount {
-    type Target = Wallet<Arc<ElectrumBlockchain>, Tree>;
+impl<B: Blockchain, D: BatchDatabase> Deref for Subaccount<B, D> {
+    type Target = Wallet<Arc<B>, D>;
 
     fn deref(&self) -> &Self::Target {
         &self.wallet
     }
 }
+
+/// Pair the unspent outputs with their status, outputs below the dust limit are marked as dust
+/// unless they have one
+fn with_status(
+    utxos: Vec<UTXO>,
+    status: &HashMap<OutPoint, UtxoStatus>,
+) -> Vec<(UTXO, UtxoStatus)> {
+    utxos
+        .into_iter()
+        .map(|utxo| {
+            let status = match status.get(&utxo.outpoint) {
+                Some(status) => *status,
+                None if utxo.txout.value < get_dust_limit(&utxo.txout.script_pubkey) => {
+                    UtxoStatus::Dust
+                }
+                None => UtxoStatus::Default,
+            };
+            (utxo, status)
+        })
+        .collect()
+}
+
+/// See [`Subaccount::get_coin_control`]
+fn select_coins(
+    utxos: Vec<(UTXO, UtxoStatus)>,
+    selected: &[OutPoint],
+) -> Result<CoinControl, Box<dyn Error>> {
+    for outpoint in selected {
+        match utxos.iter().find(|(utxo, _)| &utxo.outpoint == outpoint) {
+            None => {
+                return Err(
+                    format!("{} is not an unspent output of the subaccount", outpoint).into(),
+                )
+            }
+            Some((_, UtxoStatus::Frozen)) => return Err(format!("{} is frozen", outpoint).into()),
+            Some(_) => {}
+        }
+    }
+
+    // A selected dust output was chosen explicitly, so it can be spent
+    let unspendable = utxos
+        .into_iter()
+        .filter(|(utxo, status)| match selected.is_empty() {
+            true => *status != UtxoStatus::Default,
+            false => !selected.contains(&utxo.outpoint),
+        })
+        .map(|(utxo, _)| utxo.outpoint)
+        .collect();
+
+    Ok(CoinControl {
+        utxos: selected.to_vec(),
+        unspendable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use bitcoin::{Script, TxIn};
+
+    use bdk::blockchain::OfflineBlockchain;
+    use bdk::database::{BatchOperations, MemoryDatabase};
+
+    use crate::signer::SoftwareSigner;
+
+    type TestWallet = Wallet<Arc<OfflineBlockchain>, MemoryDatabase>;
+
+    /// Offline wallet of a subaccount, with a confirmed output of each of `values`
+    fn get_wallet(values: &[u64]) -> (TestWallet, Vec<OutPoint>) {
+        let signer = SoftwareSigner::new(
+            ExtendedPrivKey::new_master(Network::Testnet, &[0x42; 32]).unwrap(),
+        );
+        let gait_path = vec![1; 32];
+        let desc = GreenSubaccountDescriptor::from_signer(&signer, &gait_path, 1).unwrap();
+
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: vec![],
+            }],
+            output: values
+                .iter()
+                .enumerate()
+                .map(|(index, value)| TxOut {
+                    value: *value,
+                    script_pubkey: desc
+                        .get_address(index as u32, Network::Testnet)
+                        .unwrap()
+                        .script_pubkey(),
+                })
+                .collect(),
+        };
+
+        let mut database = MemoryDatabase::new();
+        let mut outpoints = Vec::new();
+        for (index, output) in funding.output.iter().enumerate() {
+            let outpoint = OutPoint::new(funding.txid(), index as u32);
+            database
+                .set_script_pubkey(&output.script_pubkey, ScriptType::External, index as u32)
+                .unwrap();
+            database
+                .set_utxo(&UTXO {
+                    outpoint,
+                    txout: output.clone(),
+                    is_internal: false,
+                })
+                .unwrap();
+            outpoints.push(outpoint);
+        }
+        database
+            .set_tx(&TransactionDetails {
+                transaction: Some(funding.clone()),
+                txid: funding.txid(),
+                timestamp: 0,
+                received: values.iter().sum(),
+                sent: 0,
+                fees: 0,
+                height: Some(100),
+            })
+            .unwrap();
+
+        let wallet = Wallet::new(
+            desc,
+            None,
+            Network::Testnet,
+            database,
+            Arc::new(OfflineBlockchain),
+        )
+        .unwrap();
+        (wallet, outpoints)
+    }
+
+    /// Inputs of a transaction sending everything the coin control allows to a third party
+    fn get_spent(wallet: &TestWallet, coin_control: CoinControl) -> HashSet<OutPoint> {
+        let to = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        let builder = TxBuilder::with_recipients(vec![(to.script_pubkey(), 0)])
+            .utxos(coin_control.utxos)
+            .unspendable(coin_control.unspendable)
+            .send_all();
+        let (psbt, _) = wallet.create_tx(builder).unwrap();
+
+        psbt.global
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect()
+    }
+
+    #[test]
+    fn test_frozen_and_dust_are_excluded() {
+        let (wallet, outpoints) = get_wallet(&[50_000, 30_000, 500]);
+        let status = vec![(outpoints[1], UtxoStatus::Frozen)]
+            .into_iter()
+            .collect();
+
+        let utxos = with_status(wallet.list_unspent().unwrap(), &status);
+        let statuses = utxos
+            .iter()
+            .map(|(utxo, status)| (utxo.outpoint, *status))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(statuses[&outpoints[0]], UtxoStatus::Default);
+        assert_eq!(statuses[&outpoints[1]], UtxoStatus::Frozen);
+        assert_eq!(statuses[&outpoints[2]], UtxoStatus::Dust);
+
+        let coin_control = select_coins(utxos, &[]).unwrap();
+        assert!(coin_control.utxos.is_empty());
+        assert_eq!(
+            get_spent(&wallet, coin_control),
+            vec![outpoints[0]].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_manual_selection_is_honored() {
+        let (wallet, outpoints) = get_wallet(&[50_000, 30_000, 500]);
+        let get_utxos = || with_status(wallet.list_unspent().unwrap(), &HashMap::new());
+
+        // Only the selected output, even if the others would be picked by the coin selection
+        let coin_control = select_coins(get_utxos(), &[outpoints[1]]).unwrap();
+        assert_eq!(
+            get_spent(&wallet, coin_control),
+            vec![outpoints[1]].into_iter().collect()
+        );
+
+        // Dust can be spent when it's chosen explicitly
+        let coin_control = select_coins(get_utxos(), &[outpoints[1], outpoints[2]]).unwrap();
+        assert_eq!(
+            get_spent(&wallet, coin_control),
+            vec![outpoints[1], outpoints[2]].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_invalid_selection() {
+        let (wallet, outpoints) = get_wallet(&[50_000, 30_000]);
+        let status = vec![(outpoints[1], UtxoStatus::Frozen)]
+            .into_iter()
+            .collect();
+        let utxos = with_status(wallet.list_unspent().unwrap(), &status);
+
+        let err = select_coins(utxos.clone(), &[outpoints[0], outpoints[1]]).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} is frozen", outpoints[1]));
+
+        let unknown = OutPoint::new(outpoints[0].txid, 2);
+        let err = select_coins(utxos, &[unknown]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{} is not an unspent output of the subaccount", unknown)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bip0039::{Language, Mnemonic, Seed};
+
+use bdk::bitcoin;
+
+use bitcoin::util::bip32::ExtendedPrivKey;
+use bitcoin::{Network, PrivateKey, Txid};
+
+use bdk::blockchain::{noop_progress, Blockchain};
+use bdk::database::{BatchDatabase, MemoryDatabase};
+use bdk::{FeeRate, TxBuilder, Wallet};
+
+use crate::subaccount::Subaccount;
+
+/// Single-sig key holding the funds to sweep
+#[derive(Debug, Clone)]
+pub enum SweepKey {
+    Wif(PrivateKey),
+    Mnemonic(ExtendedPrivKey),
+}
+
+impl SweepKey {
+    /// Parse either a WIF private key or a BIP39 mnemonic, with an optional passphrase
+    pub fn parse(key: &str, passphrase: &str, network: Network) -> Result<Self, Box<dyn Error>> {
+        let key = key.trim();
+        if !key.contains(' ') {
+            let key = PrivateKey::from_str(key)?;
+            if (key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+                return Err("the private key is for the wrong network".into());
+            }
+
+            return Ok(SweepKey::Wif(key));
+        }
+
+        let mnemonic = Mnemonic::from_phrase(key, Language::English)?;
+        let seed = Seed::new(&mnemonic, passphrase);
+
+        Ok(SweepKey::Mnemonic(ExtendedPrivKey::new_master(
+            network,
+            seed.as_bytes(),
+        )?))
+    }
+
+    /// Descriptors of the p2pkh, p2sh-p2wpkh and p2wpkh scripts of the key, with the change
+    /// descriptor for mnemonics (BIP44, BIP49 and BIP84 accounts). Uncompressed WIF keys can
+    /// only be used in p2pkh scripts.
+    fn get_descriptors(&self, network: Network) -> Vec<(&'static str, String, Option<String>)> {
+        const SCRIPT_TYPES: [(&str, &str, u32); 3] = [
+            ("p2pkh", "pkh({})", 44),
+            ("p2sh-p2wpkh", "sh(wpkh({}))", 49),
+            ("p2wpkh", "wpkh({})", 84),
+        ];
+
+        match self {
+            SweepKey::Wif(key) => SCRIPT_TYPES
+                .iter()
+                .filter(|(name, _, _)| key.compressed || *name == "p2pkh")
+                .map(|(name, template, _)| (*name, template.replace("{}", &key.to_string()), None))
+                .collect(),
+            SweepKey::Mnemonic(xprv) => {
+                let coin_type = match network {
+                    Network::Bitcoin => 0,
+                    _ => 1,
+                };
+                SCRIPT_TYPES
+                    .iter()
+                    .map(|(name, template, purpose)| {
+                        let path = |chain| {
+                            let key =
+                                format!("{}/{}'/{}'/0'/{}/*", xprv, purpose, coin_type, chain);
+                            template.replace("{}", &key)
+                        };
+
+                        (*name, path(0), Some(path(1)))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Send everything controlled by `key` to a new address of `destination`
+///
+/// Every script type is scanned with the blockchain backend in a temporary in-memory wallet
+/// that signs with `key` alone, so the Green service is not involved. One transaction is
+/// created for every script type that holds funds.
+pub fn sweep<B: Blockchain, D: BatchDatabase>(
+    key: &SweepKey,
+    network: Network,
+    client: &Arc<B>,
+    destination: &Subaccount<B, D>,
+    fee_rate: FeeRate,
+) -> Result<Vec<Txid>, Box<dyn Error>> {
+    let mut txids = Vec::new();
+    for (script_type, descriptor, change_descriptor) in key.get_descriptors(network) {
+        let wallet = Wallet::new(
+            descriptor.as_str(),
+            change_descriptor.as_deref(),
+            network,
+            MemoryDatabase::new(),
+            Arc::clone(client),
+        )?;
+        wallet.sync(noop_progress(), None)?;
+
+        let balance = wallet.get_balance()?;
+        log::info!("found {} sat in {} outputs", balance, script_type);
+        if balance == 0 {
+            continue;
+        }
+
+        // The address validator of the subaccount verifies the address with the server
+        let to = destination.get_new_address()?.script_pubkey();
+        let builder = TxBuilder::with_recipients(vec![(to, 0)])
+            .send_all()
+            .enable_rbf()
+            .fee_rate(fee_rate);
+        let (psbt, details) = wallet.create_tx(builder)?;
+
+        let (psbt, finalized) = wallet.sign(psbt, None)?;
+        if !finalized {
+            return Err("unable to finalize the sweep transaction".into());
+        }
+        txids.push(wallet.broadcast(psbt.extract_tx())?);
+
+        log::info!(
+            "swept {} sat with {} sat of fees",
+            details.sent,
+            details.fees
+        );
+    }
+
+    if txids.is_empty() {
+        return Err("no funds found for the key".into());
+    }
+    destination.sync(noop_progress(), None)?;
+
+    Ok(txids)
+}
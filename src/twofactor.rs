@@ -1,22 +1,16 @@
 use std::io::{stdin, stdout, Write};
+use std::sync::Mutex;
 
 use crate::types::TwoFactorMethod;
 
 pub trait TwoFactorResolver: std::fmt::Debug + Send + Sync {
     fn get_method(&self, available: Vec<TwoFactorMethod>) -> TwoFactorMethod;
     fn get_code(&self) -> String;
-}
-
-#[derive(Debug)]
-pub struct NoopResolver;
-
-impl TwoFactorResolver for NoopResolver {
-    fn get_method(&self, available: Vec<TwoFactorMethod>) -> TwoFactorMethod {
-        unimplemented!()
-    }
 
-    fn get_code(&self) -> String {
-        unimplemented!()
+    /// Whether the server has to send the code before `get_code` is called, `false` when the
+    /// code was requested beforehand
+    fn needs_request(&self) -> bool {
+        true
     }
 }
 
@@ -52,3 +46,47 @@ impl TwoFactorResolver for StdinResolver {
         code.trim().to_string()
     }
 }
+
+/// Resolver for codes received together with the request, like in the daemon
+///
+/// The code is set with [`ParamResolver::set`] before signing and cleared after every use.
+#[derive(Debug, Default)]
+pub struct ParamResolver {
+    twofactor: Mutex<Option<(TwoFactorMethod, String)>>,
+}
+
+impl ParamResolver {
+    pub fn set(&self, method: TwoFactorMethod, code: String) {
+        *self.twofactor.lock().unwrap() = Some((method, code));
+    }
+
+    pub fn clear(&self) {
+        *self.twofactor.lock().unwrap() = None;
+    }
+}
+
+impl TwoFactorResolver for ParamResolver {
+    fn get_method(&self, available: Vec<TwoFactorMethod>) -> TwoFactorMethod {
+        match &*self.twofactor.lock().unwrap() {
+            Some((method, _)) => *method,
+            // The server will reject the empty code
+            None => available
+                .into_iter()
+                .next()
+                .unwrap_or(TwoFactorMethod::Gauth),
+        }
+    }
+
+    fn get_code(&self) -> String {
+        self.twofactor
+            .lock()
+            .unwrap()
+            .take()
+            .map(|(_, code)| code)
+            .unwrap_or_default()
+    }
+
+    fn needs_request(&self) -> bool {
+        false
+    }
+}
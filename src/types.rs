@@ -19,11 +19,31 @@ where
         .collect())
 }
 
-#[derive(Debug, Deserialize)]
+// The server is not consistent in how it encodes numbers
+fn deserialize_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: de::Deserializer<'de>,
+    T: std::str::FromStr + de::DeserializeOwned,
+    T::Err: std::fmt::Display,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => s.parse().map_err(de::Error::custom),
+        value => T::deserialize(value).map_err(de::Error::custom),
+    }
+}
+
+fn default_min_fee() -> u64 {
+    crate::fees::DEFAULT_MIN_FEE
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthenticateSubaccount {
     pub has_txs: bool,
     pub name: String,
     pub pointer: u16,
+    /// Archived, hidden from the listings by default
+    #[serde(default)]
+    pub hidden: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,8 +58,314 @@ pub struct AuthenticateResponse {
     #[serde(deserialize_with = "deserialize_gait_path")]
     pub gait_path: Vec<u16>,
     pub earliest_key_creation_time: u64,
+    pub receiving_id: String,
     pub limits: AuthenticateLimits,
     pub subaccounts: Vec<AuthenticateSubaccount>,
+    /// Estimates keyed by confirmation target
+    #[serde(default)]
+    pub fee_estimates: HashMap<String, FeeEstimate>,
+    /// Minimum relay fee in sat/kvB
+    #[serde(default = "default_min_fee", deserialize_with = "deserialize_number")]
+    pub min_fee: u64,
+    #[serde(default, deserialize_with = "deserialize_appearance")]
+    pub appearance: Appearance,
+    #[serde(default)]
+    pub nlocktime_blocks: u32,
+    #[serde(default)]
+    pub csv_blocks: u32,
+    /// Values accepted for the CSV time
+    #[serde(default)]
+    pub csv_times: Vec<u32>,
+    #[serde(default)]
+    pub fiat_currency: String,
+    /// Pricing source of `fiat_currency`
+    #[serde(default)]
+    pub exchange: String,
+    /// Fiat per BTC, `None` when the pricing source has no rate
+    #[serde(default, deserialize_with = "deserialize_fiat_rate")]
+    pub fiat_exchange: Option<f64>,
+}
+
+fn deserialize_fiat_rate<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let rate = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    };
+
+    Ok(rate.filter(|rate| *rate > 0.0))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FiatRateResponse {
+    #[serde(default, deserialize_with = "deserialize_fiat_rate")]
+    pub fiat_exchange: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AvailableCurrencies {
+    pub all: Vec<String>,
+    /// Currencies priced by every exchange
+    pub per_exchange: HashMap<String, Vec<String>>,
+}
+
+// The server stores the appearance as an opaque JSON string
+fn deserialize_appearance<'de, D>(deserializer: D) -> Result<Appearance, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(Appearance::default()),
+        serde_json::Value::String(s) if s.is_empty() => Ok(Appearance::default()),
+        serde_json::Value::String(s) => serde_json::from_str(&s).map_err(de::Error::custom),
+        value => serde_json::from_value(value).map_err(de::Error::custom),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NotificationsSettings {
+    #[serde(default)]
+    pub email_incoming: bool,
+    #[serde(default)]
+    pub email_outgoing: bool,
+}
+
+/// Client settings stored by the server without interpreting them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Appearance {
+    /// One of `BTC`, `mBTC`, `µBTC`, `bits` or `sats`
+    #[serde(default = "default_unit")]
+    pub unit: String,
+    /// Confirmation target used for the default fee rate
+    #[serde(default = "default_required_num_blocks")]
+    pub required_num_blocks: u32,
+    /// Fee rate in sat/kvB used instead of the estimates when set
+    #[serde(default)]
+    pub custom_fee_rate: Option<u64>,
+    /// Minutes of inactivity before logging out
+    #[serde(default = "default_altimeout")]
+    pub altimeout: u32,
+    #[serde(default)]
+    pub sound: bool,
+    #[serde(default)]
+    pub notifications_settings: NotificationsSettings,
+    /// Keys of other clients, kept when the appearance is written back
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+fn default_unit() -> String {
+    "BTC".to_string()
+}
+
+fn default_required_num_blocks() -> u32 {
+    12
+}
+
+fn default_altimeout() -> u32 {
+    5
+}
+
+impl Default for Appearance {
+    fn default() -> Self {
+        Appearance {
+            unit: default_unit(),
+            required_num_blocks: default_required_num_blocks(),
+            custom_fee_rate: None,
+            altimeout: default_altimeout(),
+            sound: false,
+            notifications_settings: NotificationsSettings::default(),
+            other: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PricingSource {
+    pub currency: String,
+    pub exchange: String,
+}
+
+/// Account settings, changing `nlocktime` or `csvtime` requires 2FA
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(flatten)]
+    pub appearance: Appearance,
+    pub pricing: PricingSource,
+    /// Blocks after which the nLockTime recovery transactions become valid
+    pub nlocktime: u32,
+    /// Blocks after which the CSV recovery path can be used
+    pub csvtime: u32,
+}
+
+impl From<&AuthenticateResponse> for Settings {
+    fn from(auth_response: &AuthenticateResponse) -> Self {
+        Settings {
+            appearance: auth_response.appearance.clone(),
+            pricing: PricingSource {
+                currency: auth_response.fiat_currency.clone(),
+                exchange: auth_response.exchange.clone(),
+            },
+            nlocktime: auth_response.nlocktime_blocks,
+            csvtime: auth_response.csv_blocks,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FeeEstimate {
+    /// BTC/kvB, negative when the server doesn't have an estimate
+    #[serde(deserialize_with = "deserialize_number")]
+    pub feerate: f64,
+    #[serde(deserialize_with = "deserialize_number")]
+    pub blocks: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockEvent {
+    pub count: u32,
+    pub block_hash: Option<bitcoin::BlockHash>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransactionEvent {
+    pub txhash: bitcoin::Txid,
+    #[serde(default)]
+    pub subaccounts: Vec<u16>,
+    pub value: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum GreenEvent {
+    Block(BlockEvent),
+    Transaction(TransactionEvent),
+    FeeEstimates(HashMap<String, FeeEstimate>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerTransaction {
+    pub txhash: bitcoin::Txid,
+    /// `null` or empty when the transaction has no memo
+    #[serde(default)]
+    pub memo: Option<String>,
+    pub created_at: String,
+    /// `null` while unconfirmed
+    #[serde(default)]
+    pub block_height: Option<u32>,
+    #[serde(default)]
+    pub fee: u64,
+}
+
+impl ServerTransaction {
+    /// Unix time of `created_at`, when the server first saw the transaction
+    pub fn get_created_at(&self) -> Option<u64> {
+        parse_server_time(&self.created_at)
+    }
+}
+
+/// Parse the UTC times sent by the server, `YYYY-MM-DD HH:MM:SS` optionally with the ISO 8601
+/// `T` separator, fractional seconds and `Z` suffix
+fn parse_server_time(time: &str) -> Option<u64> {
+    let time = time.trim_end_matches('Z');
+    let (date, time) = time.split_at(time.find(|c| c == ' ' || c == 'T')?);
+    let time = time[1..].split('.').next()?;
+
+    let parse = |s: &str, separator| -> Option<Vec<i64>> {
+        let fields = s
+            .split(separator)
+            .map(|n| n.parse().ok())
+            .collect::<Option<Vec<i64>>>()?;
+        match fields.len() {
+            3 => Some(fields),
+            _ => None,
+        }
+    };
+    let (date, time) = (parse(date, '-')?, parse(time, ':')?);
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch, from http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let timestamp = days * 86_400 + time[0] * 3_600 + time[1] * 60 + time[2];
+    match timestamp {
+        t if t >= 0 => Some(t as u64),
+        _ => None,
+    }
+}
+
+/// Status of an unspent output, frozen ones are never selected when creating transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UtxoStatus {
+    Default,
+    Frozen,
+    /// Not worth spending, set locally for outputs below the dust limit
+    Dust,
+}
+
+impl Default for UtxoStatus {
+    fn default() -> Self {
+        UtxoStatus::Default
+    }
+}
+
+/// The server encodes the status as an integer, unlike the lowercase names used locally
+mod server_utxo_status {
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    use super::UtxoStatus;
+
+    pub fn serialize<S: Serializer>(status: &UtxoStatus, serializer: S) -> Result<S::Ok, S::Error> {
+        match status {
+            UtxoStatus::Default => serializer.serialize_u8(0),
+            UtxoStatus::Frozen => serializer.serialize_u8(1),
+            UtxoStatus::Dust => Err(ser::Error::custom(
+                "the dust status can't be stored on the server",
+            )),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UtxoStatus, D::Error> {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(UtxoStatus::Default),
+            1 => Ok(UtxoStatus::Frozen),
+            status => Err(de::Error::custom(format!("unknown utxo status {}", status))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerUnspentOutput {
+    pub txhash: bitcoin::Txid,
+    pub pt_idx: u32,
+    pub value: u64,
+    #[serde(default, with = "server_utxo_status")]
+    pub user_status: UtxoStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UtxoStatusChange {
+    pub txhash: bitcoin::Txid,
+    pub pt_idx: u32,
+    #[serde(with = "server_utxo_status")]
+    pub user_status: UtxoStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransactionListResponse {
+    pub list: Vec<ServerTransaction>,
+    pub next_page_id: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -97,4 +423,50 @@ impl TwoFactorConfigResponse {
 pub struct TwoFactorData {
     pub code: String,
     pub method: TwoFactorMethod,
+    /// Fee increase authorized by the code, only for fee bumps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bump_fee_amount: Option<u64>,
+}
+
+/// Operation a 2FA code is requested for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwoFactorAction {
+    SendRawTx,
+    /// Replacement of a transaction, increasing its fee by `amount` satoshi
+    BumpFee {
+        amount: u64,
+    },
+    SetNlocktime {
+        value: u32,
+    },
+    SetCsvtime {
+        value: u32,
+    },
+}
+
+impl TwoFactorAction {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            TwoFactorAction::SendRawTx => "send_raw_tx",
+            TwoFactorAction::BumpFee { .. } => "bump_fee",
+            TwoFactorAction::SetNlocktime { .. } => "set_nlocktime",
+            TwoFactorAction::SetCsvtime { .. } => "set_csvtime",
+        }
+    }
+
+    pub fn get_data(&self) -> Option<serde_json::Value> {
+        match self {
+            TwoFactorAction::SendRawTx => None,
+            TwoFactorAction::BumpFee { amount } => Some(serde_json::json!({ "amount": amount })),
+            TwoFactorAction::SetNlocktime { value } | TwoFactorAction::SetCsvtime { value } => {
+                Some(serde_json::json!({ "value": value }))
+            }
+        }
+    }
+}
+
+impl Default for TwoFactorAction {
+    fn default() -> Self {
+        TwoFactorAction::SendRawTx
+    }
 }
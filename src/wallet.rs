@@ -1,7 +1,192 @@
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::sync::Arc;
 
+use serde::Serialize;
+
+use tokio::sync::broadcast::RecvError;
+
+use bdk::bitcoin::OutPoint;
+use bdk::blockchain::{noop_progress, Blockchain, ElectrumBlockchain};
+use bdk::database::BatchDatabase;
+use bdk::sled::Tree;
+use bdk::{FeeRate, TransactionDetails, TxBuilder};
+
+use crate::fiat::FiatAmount;
 use crate::ga::GAClient;
+use crate::metadata::SubaccountMetadata;
+use crate::subaccount::Subaccount;
+use crate::types::GreenEvent;
 
-pub struct GreenWallet {
+/// Subaccount as shown in the listings, with its server state and local metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct SubaccountInfo {
+    pub pointer: u16,
+    pub name: String,
+    pub hidden: bool,
+    pub balance: u64,
+    pub fiat_balance: Option<FiatAmount>,
+    #[serde(flatten)]
+    pub metadata: SubaccountMetadata,
+}
+
+pub struct GreenWallet<B: Blockchain = ElectrumBlockchain, D: BatchDatabase = Tree> {
     session: Arc<GAClient>,
+    subaccounts: BTreeMap<u16, Subaccount<B, D>>,
+}
+
+impl<B: Blockchain, D: BatchDatabase> GreenWallet<B, D> {
+    pub fn new(session: Arc<GAClient>) -> Self {
+        GreenWallet {
+            session,
+            subaccounts: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_subaccount(&mut self, pointer: u16, subaccount: Subaccount<B, D>) {
+        self.subaccounts.insert(pointer, subaccount);
+    }
+
+    pub fn get_subaccount(&self, pointer: u16) -> Option<&Subaccount<B, D>> {
+        self.subaccounts.get(&pointer)
+    }
+
+    pub fn get_session(&self) -> &Arc<GAClient> {
+        &self.session
+    }
+
+    /// Name of the subaccount on the server
+    pub fn get_subaccount_name(&self, pointer: u16) -> String {
+        match pointer {
+            0 => "Main Account".to_string(),
+            _ => self
+                .session
+                .get_subaccounts()
+                .into_iter()
+                .find(|s| s.pointer == pointer)
+                .map(|s| s.name)
+                .unwrap_or_else(|| format!("Subaccount {}", pointer)),
+        }
+    }
+
+    /// Subaccounts added to the wallet, sorted by pointer. Archived ones are skipped unless
+    /// `include_hidden` is set, the main account can't be archived.
+    pub fn list_subaccounts(
+        &self,
+        include_hidden: bool,
+    ) -> Result<Vec<SubaccountInfo>, Box<dyn Error>> {
+        let server_subaccounts = self.session.get_subaccounts();
+
+        let mut listing = Vec::new();
+        for (pointer, subaccount) in &self.subaccounts {
+            let hidden = server_subaccounts
+                .iter()
+                .any(|s| s.pointer == *pointer && s.hidden);
+            if hidden && !include_hidden {
+                continue;
+            }
+
+            listing.push(SubaccountInfo {
+                pointer: *pointer,
+                name: self.get_subaccount_name(*pointer),
+                hidden,
+                balance: subaccount.get_balance()?,
+                fiat_balance: subaccount.get_fiat_balance()?,
+                metadata: subaccount.get_metadata()?,
+            });
+        }
+
+        Ok(listing)
+    }
+
+    /// Move `amount`, or everything spendable when `None`, from subaccount `from` to a new address
+    /// of subaccount `to`
+    ///
+    /// The address is checked with the server before being used, and the transaction gets a memo
+    /// naming both subaccounts so that it's recognizable in the history of either.
+    pub async fn transfer(
+        &self,
+        from: u16,
+        to: u16,
+        amount: Option<u64>,
+        fee_rate: FeeRate,
+        utxos: &[OutPoint],
+    ) -> Result<TransactionDetails, Box<dyn Error>> {
+        if from == to {
+            return Err("the source and destination subaccounts are the same".into());
+        }
+        let source = self
+            .get_subaccount(from)
+            .ok_or("unknown source subaccount")?;
+        let destination = self
+            .get_subaccount(to)
+            .ok_or("unknown destination subaccount")?;
+
+        // The address validator of the destination verifies the address with the server
+        let address = destination.get_new_address()?;
+
+        let coin_control = source.get_coin_control(utxos)?;
+        let mut builder =
+            TxBuilder::with_recipients(vec![(address.script_pubkey(), amount.unwrap_or(0))])
+                .utxos(coin_control.utxos)
+                .unspendable(coin_control.unspendable)
+                .enable_rbf()
+                .fee_rate(fee_rate);
+        if amount.is_none() {
+            builder = builder.send_all();
+        }
+        let (psbt, details) = source.create_tx(builder)?;
+
+        let (psbt, finalized) = source.sign(psbt, None)?;
+        if !finalized {
+            return Err("unable to finalize the transfer".into());
+        }
+        let txid = source.broadcast(psbt.extract_tx())?;
+
+        let memo = format!(
+            "Transfer from {} to {}",
+            self.get_subaccount_name(from),
+            self.get_subaccount_name(to)
+        );
+        self.session.set_memo(&txid, &memo).await?;
+
+        for subaccount in &[source, destination] {
+            subaccount.sync(noop_progress(), None)?;
+        }
+
+        Ok(details)
+    }
+
+    /// Wait for the server notifications, syncing the subaccounts involved in every new
+    /// transaction before passing the event to `callback`
+    pub async fn watch<F>(&self, mut callback: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&GreenEvent),
+    {
+        let mut events = self.session.subscribe_events();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("skipped {} notifications, syncing everything", skipped);
+
+                    for subaccount in self.subaccounts.values() {
+                        subaccount.sync(noop_progress(), None)?;
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+
+            if let GreenEvent::Transaction(tx) = &event {
+                for pointer in &tx.subaccounts {
+                    if let Some(subaccount) = self.subaccounts.get(pointer) {
+                        subaccount.sync(noop_progress(), None)?;
+                    }
+                }
+            }
+
+            callback(&event);
+        }
+    }
 }
@@ -0,0 +1,149 @@
+use std::fs;
+use std::str::FromStr;
+
+use bdk::bitcoin;
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+use neerg::airgap::{check_signed_psbt, export_psbt, import_psbt, psbt_to_base64};
+use neerg::{SoftwareSigner, UserSigner};
+
+fn get_signer(seed: u8) -> SoftwareSigner {
+    SoftwareSigner::new(ExtendedPrivKey::new_master(Network::Testnet, &[seed; 32]).unwrap())
+}
+
+fn get_path() -> DerivationPath {
+    DerivationPath::from_str("m/1/0").unwrap()
+}
+
+/// PSBT spending a 2of2 p2sh-p2wsh output like the ones of the subaccounts, with the user key of
+/// `signer` and a service key
+fn get_unsigned_psbt(signer: &dyn UserSigner, value: u64) -> PartiallySignedTransaction {
+    let path = get_path();
+    let user_key = signer.get_xpub(&path).unwrap().public_key;
+    let service_key = get_signer(0x01).get_xpub(&path).unwrap().public_key;
+
+    let witness_script = Builder::new()
+        .push_int(2)
+        .push_key(&service_key)
+        .push_key(&user_key)
+        .push_int(2)
+        .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+        .into_script();
+    let redeem_script = witness_script.to_v0_p2wsh();
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Script::new(),
+            sequence: 0xFFFF_FFFD,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: redeem_script.to_p2sh(),
+        }],
+    };
+
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+    psbt.inputs[0].witness_utxo = Some(TxOut {
+        value: 10_000,
+        script_pubkey: redeem_script.to_p2sh(),
+    });
+    psbt.inputs[0].redeem_script = Some(redeem_script);
+    psbt.inputs[0].witness_script = Some(witness_script);
+    psbt.inputs[0]
+        .hd_keypaths
+        .insert(user_key, (signer.get_fingerprint().unwrap(), path));
+
+    psbt
+}
+
+#[test]
+fn test_export_sign_import() {
+    let signer = get_signer(0x42);
+    let dir = std::env::temp_dir().join(format!("neerg-airgap-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // Online machine: export the unsigned PSBT
+    let unsigned = get_unsigned_psbt(&signer, 9_000);
+    let unsigned_path = dir.join("unsigned.psbt");
+    export_psbt(&unsigned, fs::File::create(&unsigned_path).unwrap(), true).unwrap();
+
+    // Offline machine: import, sign and export it again
+    let mut psbt = import_psbt(unsigned_path.to_str().unwrap()).unwrap();
+    assert_eq!(serialize(&psbt), serialize(&unsigned));
+    signer.sign_psbt(&mut psbt).unwrap();
+    check_signed_psbt(&psbt, signer.get_fingerprint().unwrap()).unwrap();
+
+    let signed_path = dir.join("signed.psbt");
+    let mut signed = Vec::new();
+    export_psbt(&psbt, &mut signed, false).unwrap();
+    assert_eq!(
+        String::from_utf8(signed.clone()).unwrap().trim(),
+        psbt_to_base64(&psbt)
+    );
+    fs::write(&signed_path, signed).unwrap();
+
+    // Online machine: import the signed PSBT, either from the base64 file or directly
+    let imported = import_psbt(signed_path.to_str().unwrap()).unwrap();
+    assert_eq!(serialize(&imported), serialize(&psbt));
+    assert_eq!(imported.inputs[0].partial_sigs.len(), 1);
+    check_signed_psbt(&imported, signer.get_fingerprint().unwrap()).unwrap();
+
+    let imported = import_psbt(&psbt_to_base64(&psbt)).unwrap();
+    assert_eq!(serialize(&imported), serialize(&psbt));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_foreign_psbt_is_rejected() {
+    let signer = get_signer(0x42);
+    let fingerprint = signer.get_fingerprint().unwrap();
+
+    // Signed by another wallet, none of the keys are ours
+    let other = get_signer(0x43);
+    let mut foreign = get_unsigned_psbt(&other, 9_000);
+    other.sign_psbt(&mut foreign).unwrap();
+    assert_eq!(
+        check_signed_psbt(&foreign, fingerprint)
+            .unwrap_err()
+            .to_string(),
+        "input 0 doesn't belong to the wallet"
+    );
+
+    // Ours, but the offline machine didn't sign it
+    let unsigned = get_unsigned_psbt(&signer, 9_000);
+    assert_eq!(
+        check_signed_psbt(&unsigned, fingerprint)
+            .unwrap_err()
+            .to_string(),
+        "input 0 is not signed"
+    );
+}
+
+#[test]
+fn test_mismatched_psbt_is_rejected() {
+    let signer = get_signer(0x42);
+
+    let mut signed = get_unsigned_psbt(&signer, 9_000);
+    signer.sign_psbt(&mut signed).unwrap();
+
+    // Same input and keys, but the signature was made for a transaction with another output
+    let mut mismatched = get_unsigned_psbt(&signer, 8_000);
+    mismatched.inputs[0].partial_sigs = signed.inputs[0].partial_sigs.clone();
+    assert_eq!(
+        check_signed_psbt(&mismatched, signer.get_fingerprint().unwrap())
+            .unwrap_err()
+            .to_string(),
+        "invalid signature for input 0"
+    );
+}
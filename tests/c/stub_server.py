@@ -0,0 +1,377 @@
+#!/usr/bin/env python3
+"""
+Stand-in for the Green server and an Electrum server, enough for test_ffi to login, create, sign
+and send a transaction without touching the network.
+
+Usage: stub_server.py WAMP_PORT ELECTRUM_PORT
+
+The Green side speaks WAMP over a websocket and "co-signs" with a dummy signature, which the
+client doesn't verify. The Electrum side serves the transactions created by the `stub.fund`
+method, called by the test to fund one of its addresses, and accepts any broadcast.
+"""
+
+import base64
+import hashlib
+import json
+import os
+import socketserver
+import struct
+import sys
+import threading
+
+TWOFACTOR_CODE = "123456"
+
+# DER signature with r = s = 1 followed by SIGHASH_ALL
+DUMMY_SIGNATURE = bytes.fromhex("300602010102010101")
+
+# Testnet genesis block header, good enough for any height
+HEADER = (
+    "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c"
+    "3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d1aa4ae18"
+)
+
+WEBSOCKET_GUID = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11"
+
+lock = threading.Lock()
+transactions = {}
+histories = {}
+
+
+def sha256d(data):
+    return hashlib.sha256(hashlib.sha256(data).digest()).digest()
+
+
+def varint(n):
+    if n < 0xFD:
+        return bytes([n])
+    if n <= 0xFFFF:
+        return b"\xfd" + struct.pack("<H", n)
+    return b"\xfe" + struct.pack("<I", n)
+
+
+def read_varint(data, pos):
+    n = data[pos]
+    if n < 0xFD:
+        return n, pos + 1
+    if n == 0xFD:
+        return struct.unpack_from("<H", data, pos + 1)[0], pos + 3
+    return struct.unpack_from("<I", data, pos + 1)[0], pos + 5
+
+
+def parse_tx(data):
+    """Split a transaction in (version, inputs, outputs, witnesses, locktime)"""
+    pos = 4
+    version = data[:4]
+    segwit = data[4] == 0 and data[5] == 1
+    if segwit:
+        pos += 2
+
+    inputs = []
+    count, pos = read_varint(data, pos)
+    for _ in range(count):
+        start = pos
+        length, pos = read_varint(data, pos + 36)
+        pos += length + 4
+        inputs.append(data[start:pos])
+
+    outputs = []
+    count, pos = read_varint(data, pos)
+    for _ in range(count):
+        start = pos
+        length, pos = read_varint(data, pos + 8)
+        pos += length
+        outputs.append(data[start:pos])
+
+    witnesses = []
+    for _ in inputs if segwit else []:
+        stack = []
+        items, pos = read_varint(data, pos)
+        for _ in range(items):
+            length, pos = read_varint(data, pos)
+            stack.append(data[pos : pos + length])
+            pos += length
+        witnesses.append(stack)
+
+    return version, inputs, outputs, witnesses, data[pos : pos + 4]
+
+
+def serialize_tx(version, inputs, outputs, witnesses, locktime):
+    data = version
+    if any(witnesses):
+        data += b"\x00\x01"
+    data += varint(len(inputs)) + b"".join(inputs)
+    data += varint(len(outputs)) + b"".join(outputs)
+    if any(witnesses):
+        for stack in witnesses:
+            data += varint(len(stack))
+            data += b"".join(varint(len(item)) + item for item in stack)
+    return data + locktime
+
+
+def get_txid(data):
+    version, inputs, outputs, _, locktime = parse_tx(data)
+    return sha256d(serialize_tx(version, inputs, outputs, [], locktime))[::-1].hex()
+
+
+def get_scripthash(script):
+    return hashlib.sha256(script).digest()[::-1].hex()
+
+
+def base58_decode(address):
+    alphabet = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz"
+    n = 0
+    for c in address:
+        n = n * 58 + alphabet.index(c)
+    data = n.to_bytes(25, "big")
+    if sha256d(data[:21])[:4] != data[21:]:
+        raise ValueError("invalid checksum")
+    return data[:21]
+
+
+def address_to_script(address):
+    """Only the testnet P2SH addresses of the subaccounts are supported"""
+    payload = base58_decode(address)
+    if payload[0] != 0xC4:
+        raise ValueError("not a testnet p2sh address")
+    return b"\xa9\x14" + payload[1:] + b"\x87"
+
+
+def add_transaction(data, scripts):
+    txid = get_txid(data)
+    with lock:
+        transactions[txid] = data.hex()
+        for script in scripts:
+            histories.setdefault(get_scripthash(script), []).append(
+                {"tx_hash": txid, "height": 0}
+            )
+    return txid
+
+
+def fund(address, satoshi):
+    script = address_to_script(address)
+
+    # The funding transaction spends a parent that is served too, in case its inputs are looked up
+    coinbase_input = b"\x00" * 32 + b"\xff\xff\xff\xff" + b"\x02" + os.urandom(2) + b"\xff" * 4
+    anyone_can_spend = struct.pack("<Q", satoshi + 1000) + b"\x01\x51"
+    parent = serialize_tx(
+        b"\x02\x00\x00\x00", [coinbase_input], [anyone_can_spend], [], b"\x00" * 4
+    )
+    parent_txid = add_transaction(parent, [])
+
+    spend_input = bytes.fromhex(parent_txid)[::-1] + b"\x00" * 4 + b"\x00" + b"\xff" * 4
+    output = struct.pack("<Q", satoshi) + varint(len(script)) + script
+    tx = serialize_tx(b"\x02\x00\x00\x00", [spend_input], [output], [], b"\x00" * 4)
+    return add_transaction(tx, [script])
+
+
+def handle_electrum(method, params):
+    if method == "server.version":
+        return ["stub", "1.4"]
+    if method == "server.ping":
+        return None
+    if method == "blockchain.headers.subscribe":
+        return {"height": 100, "hex": HEADER}
+    if method == "blockchain.block.header":
+        return HEADER
+    if method == "blockchain.estimatefee":
+        return 0.0001
+    if method == "blockchain.relayfee":
+        return 0.00001
+    if method == "blockchain.scripthash.get_history":
+        with lock:
+            return histories.get(params[0], [])
+    if method == "blockchain.transaction.get":
+        with lock:
+            return transactions[params[0]]
+    if method == "blockchain.transaction.broadcast":
+        return add_transaction(bytes.fromhex(params[0]), [])
+    if method == "stub.fund":
+        return fund(params[0], params[1])
+    raise ValueError("unsupported method {}".format(method))
+
+
+class ElectrumHandler(socketserver.StreamRequestHandler):
+    def answer(self, request):
+        response = {"jsonrpc": "2.0", "id": request["id"]}
+        try:
+            response["result"] = handle_electrum(request["method"], request.get("params", []))
+        except Exception as e:
+            response["error"] = {"code": 1, "message": str(e)}
+        return response
+
+    def handle(self):
+        for line in self.rfile:
+            if not line.strip():
+                continue
+            request = json.loads(line)
+            if isinstance(request, list):
+                response = [self.answer(r) for r in request]
+            else:
+                response = self.answer(request)
+            self.wfile.write(json.dumps(response).encode() + b"\n")
+            self.wfile.flush()
+
+
+def get_auth_response():
+    return {
+        "gait_path": "00" * 64,
+        "earliest_key_creation_time": 1600000000,
+        "receiving_id": "GA3stub",
+        "limits": {"is_fiat": False, "per_tx": 0, "total": 0},
+        "subaccounts": [],
+        "fee_estimates": {
+            "3": {"feerate": "0.00002000", "blocks": "3"},
+            "12": {"feerate": "0.00001000", "blocks": "12"},
+        },
+        "min_fee": "1000",
+        "appearance": "",
+        "nlocktime_blocks": 12960,
+        "csv_blocks": 0,
+        "csv_times": [],
+        "fiat_currency": "USD",
+        "exchange": "BITSTAMP",
+        "fiat_exchange": "10000",
+    }
+
+
+def sign_raw_tx(raw_tx, twofactor_data):
+    if twofactor_data != {"code": TWOFACTOR_CODE, "method": "gauth"}:
+        raise ValueError("invalid two factor data {}".format(twofactor_data))
+
+    version, inputs, outputs, witnesses, locktime = parse_tx(bytes.fromhex(raw_tx))
+    # The client reads the service signature from the second witness item
+    witnesses = [[b"", DUMMY_SIGNATURE] + stack for stack in witnesses]
+    return {"tx": serialize_tx(version, inputs, outputs, witnesses, locktime).hex()}
+
+
+class GreenHandler(socketserver.StreamRequestHandler):
+    address_pointer = 0
+
+    def handshake(self):
+        headers = {}
+        self.rfile.readline()
+        for line in self.rfile:
+            line = line.decode().strip()
+            if not line:
+                break
+            name, value = line.split(":", 1)
+            headers[name.strip().lower()] = value.strip()
+
+        accept = base64.b64encode(
+            hashlib.sha1((headers["sec-websocket-key"] + WEBSOCKET_GUID).encode()).digest()
+        ).decode()
+        self.wfile.write(
+            (
+                "HTTP/1.1 101 Switching Protocols\r\n"
+                "Upgrade: websocket\r\n"
+                "Connection: Upgrade\r\n"
+                "Sec-WebSocket-Accept: {}\r\n"
+                "Sec-WebSocket-Protocol: wamp.2.json\r\n\r\n"
+            )
+            .format(accept)
+            .encode()
+        )
+        self.wfile.flush()
+
+    def read_frame(self):
+        head = self.rfile.read(2)
+        if len(head) < 2:
+            return None, None
+        opcode = head[0] & 0x0F
+        length = head[1] & 0x7F
+        if length == 126:
+            length = struct.unpack(">H", self.rfile.read(2))[0]
+        elif length == 127:
+            length = struct.unpack(">Q", self.rfile.read(8))[0]
+        mask = self.rfile.read(4) if head[1] & 0x80 else b"\x00" * 4
+        payload = bytes(b ^ mask[i % 4] for i, b in enumerate(self.rfile.read(length)))
+        return opcode, payload
+
+    def write_frame(self, opcode, payload):
+        if len(payload) < 126:
+            head = struct.pack(">BB", 0x80 | opcode, len(payload))
+        elif len(payload) < 0x10000:
+            head = struct.pack(">BBH", 0x80 | opcode, 126, len(payload))
+        else:
+            head = struct.pack(">BBQ", 0x80 | opcode, 127, len(payload))
+        self.wfile.write(head + payload)
+        self.wfile.flush()
+
+    def send(self, message):
+        self.write_frame(0x1, json.dumps(message).encode())
+
+    def call(self, procedure, args):
+        if procedure == "com.greenaddress.login.get_trezor_challenge":
+            return "0123456789abcdef"
+        if procedure == "com.greenaddress.login.authenticate":
+            return get_auth_response()
+        if procedure == "com.greenaddress.twofactor.get_config":
+            return {
+                "email": False,
+                "gauth": True,
+                "phone": False,
+                "sms": False,
+                "any": True,
+                "email_confirmed": False,
+            }
+        if procedure == "com.greenaddress.txs.get_all_unspent_outputs":
+            return []
+        if procedure == "com.greenaddress.vault.fund":
+            self.address_pointer += 1
+            return {
+                "addr_type": "p2wsh",
+                "branch": 1,
+                "pointer": self.address_pointer,
+                "script": "51",
+                "subaccount": args[0],
+            }
+        if procedure == "com.greenaddress.vault.sign_raw_tx":
+            return sign_raw_tx(args[0], args[1])
+        raise ValueError("unsupported procedure {}".format(procedure))
+
+    def handle(self):
+        self.handshake()
+        while True:
+            opcode, payload = self.read_frame()
+            if opcode is None or opcode == 0x8:
+                break
+            if opcode == 0x9:
+                self.write_frame(0xA, payload)
+                continue
+            if opcode != 0x1:
+                continue
+
+            message = json.loads(payload)
+            kind, request = message[0], message[1] if len(message) > 1 else None
+            if kind == 1:  # HELLO
+                self.send([2, 1, {"roles": {"broker": {}, "dealer": {}}}])
+            elif kind == 6:  # GOODBYE
+                self.send([6, {}, "wamp.close.goodbye_and_out"])
+                break
+            elif kind == 32:  # SUBSCRIBE
+                self.send([33, request, request])
+            elif kind == 48:  # CALL
+                try:
+                    result = self.call(message[3], message[4] if len(message) > 4 else [])
+                    self.send([50, request, {}, [result]])
+                except Exception as e:
+                    self.send([8, 48, request, {}, "com.greenaddress.error", [str(e)]])
+
+
+class Server(socketserver.ThreadingTCPServer):
+    allow_reuse_address = True
+    daemon_threads = True
+
+
+def main():
+    wamp_port, electrum_port = int(sys.argv[1]), int(sys.argv[2])
+
+    green = Server(("127.0.0.1", wamp_port), GreenHandler)
+    electrum = Server(("127.0.0.1", electrum_port), ElectrumHandler)
+    threading.Thread(target=green.serve_forever, daemon=True).start()
+    print("stub listening on {} and {}".format(wamp_port, electrum_port), flush=True)
+    electrum.serve_forever()
+
+
+if __name__ == "__main__":
+    main()
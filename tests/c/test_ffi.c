@@ -0,0 +1,336 @@
+/*
+ * Drives the C interface of neerg. The offline checks always run, the stub ones when
+ * NEERG_TEST_STUB_ENDPOINT and NEERG_TEST_STUB_ELECTRUM point to stub_server.py and the login
+ * ones only when NEERG_TEST_MNEMONIC contains the mnemonic of a testnet wallet.
+ */
+
+#include <netdb.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/socket.h>
+#include <unistd.h>
+
+#include "neerg.h"
+
+#define STUB_MNEMONIC                                                                       \
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon " \
+    "about"
+#define STUB_2FA_CODE "123456"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                  \
+            failures++;                                                      \
+        }                                                                    \
+    } while (0)
+
+static void print_error(void)
+{
+    char *details = NULL;
+    GA_get_thread_error_details(&details);
+    fprintf(stderr, "error: %s\n", details);
+    GA_destroy_string(details);
+}
+
+static int twofactor_callback(void *context, const char *request, char *response,
+                              size_t response_len)
+{
+    const char *code = getenv("NEERG_TEST_2FA_CODE");
+    (void)context;
+
+    if (strstr(request, "get_method") != NULL) {
+        snprintf(response, response_len, "\"gauth\"");
+    } else {
+        snprintf(response, response_len, "\"%s\"", code != NULL ? code : "");
+    }
+    return GA_OK;
+}
+
+struct stub_callback_state {
+    int methods;
+    int codes;
+};
+
+/* Answers like a user with Google Authenticator, checking that the chosen method is passed */
+static int stub_twofactor_callback(void *context, const char *request, char *response,
+                                   size_t response_len)
+{
+    struct stub_callback_state *state = context;
+
+    if (strstr(request, "get_method") != NULL) {
+        state->methods++;
+        snprintf(response, response_len, "\"gauth\"");
+        return GA_OK;
+    }
+    if (strstr(request, "\"method\":\"gauth\"") == NULL) {
+        return GA_ERROR;
+    }
+    state->codes++;
+    snprintf(response, response_len, "\"%s\"", STUB_2FA_CODE);
+    return GA_OK;
+}
+
+/* Copy the string value of `key` from the compact JSON `json` */
+static int get_json_string(const char *json, const char *key, char *value, size_t value_len)
+{
+    char pattern[64];
+    const char *start, *end;
+
+    snprintf(pattern, sizeof(pattern), "\"%s\":\"", key);
+    start = json != NULL ? strstr(json, pattern) : NULL;
+    if (start == NULL) {
+        return -1;
+    }
+    start += strlen(pattern);
+    end = strchr(start, '"');
+    if (end == NULL || (size_t)(end - start) >= value_len) {
+        return -1;
+    }
+
+    memcpy(value, start, end - start);
+    value[end - start] = '\0';
+    return 0;
+}
+
+/* Send a single JSON-RPC request to the Electrum side of the stub, `address` is `host:port` */
+static int stub_electrum_call(const char *address, const char *request, char *response,
+                              size_t response_len)
+{
+    char host[256];
+    const char *port = strrchr(address, ':');
+    struct addrinfo hints, *info = NULL;
+    size_t received = 0;
+    ssize_t n;
+    int fd;
+
+    if (port == NULL || (size_t)(port - address) >= sizeof(host)) {
+        return -1;
+    }
+    memcpy(host, address, port - address);
+    host[port - address] = '\0';
+
+    memset(&hints, 0, sizeof(hints));
+    hints.ai_socktype = SOCK_STREAM;
+    if (getaddrinfo(host, port + 1, &hints, &info) != 0) {
+        return -1;
+    }
+    fd = socket(info->ai_family, info->ai_socktype, info->ai_protocol);
+    if (fd < 0 || connect(fd, info->ai_addr, info->ai_addrlen) != 0) {
+        freeaddrinfo(info);
+        if (fd >= 0) {
+            close(fd);
+        }
+        return -1;
+    }
+    freeaddrinfo(info);
+
+    if (write(fd, request, strlen(request)) != (ssize_t)strlen(request)) {
+        close(fd);
+        return -1;
+    }
+    while (received + 1 < response_len &&
+           (n = read(fd, response + received, response_len - received - 1)) > 0) {
+        received += n;
+        if (response[received - 1] == '\n') {
+            break;
+        }
+    }
+    response[received] = '\0';
+    close(fd);
+
+    return received > 0 ? 0 : -1;
+}
+
+static GA_session *stub_login(const char *endpoint, const char *electrum, const char *database,
+                              struct stub_callback_state *state)
+{
+    GA_session *session = NULL;
+    char config[1024];
+
+    snprintf(config, sizeof(config),
+             "{\"endpoint\": \"%s\", \"electrum_url\": \"tcp://%s\", \"database_path\": "
+             "\"%s\", \"timeout\": 10}",
+             endpoint, electrum, database);
+    CHECK(GA_create_session(config, &session) == GA_OK);
+    CHECK(GA_set_twofactor_callback(session, stub_twofactor_callback, state) == GA_OK);
+
+    if (GA_login(session, "{\"mnemonic\": \"" STUB_MNEMONIC "\"}") != GA_OK) {
+        print_error();
+        failures++;
+        GA_destroy_session(session);
+        return NULL;
+    }
+    return session;
+}
+
+/* Fund an address of the wallet through the stub, then spend it from a new session */
+static void test_stub(const char *endpoint, const char *electrum)
+{
+    struct stub_callback_state state = {0, 0};
+    GA_session *session = NULL;
+    char database[64], address[128], details[8192], response[1024], txid[65];
+    char *psbt = malloc(sizeof(details));
+    char *output = NULL;
+
+    snprintf(database, sizeof(database), "neerg-test-stub-db-%d-funding", (int)getpid());
+    session = stub_login(endpoint, electrum, database, &state);
+    if (session == NULL) {
+        free(psbt);
+        return;
+    }
+    CHECK(GA_get_receive_address(session, "{\"subaccount\": 0}", &output) == GA_OK);
+    CHECK(get_json_string(output, "address", address, sizeof(address)) == 0);
+    GA_destroy_string(output);
+    CHECK(GA_destroy_session(session) == GA_OK);
+
+    snprintf(details, sizeof(details),
+             "{\"id\": 1, \"method\": \"stub.fund\", \"params\": [\"%s\", 100000]}\n", address);
+    CHECK(stub_electrum_call(electrum, details, response, sizeof(response)) == 0);
+    CHECK(strstr(response, "\"result\"") != NULL);
+
+    /* A new database syncs the funding transaction from scratch */
+    snprintf(database, sizeof(database), "neerg-test-stub-db-%d-spending", (int)getpid());
+    session = stub_login(endpoint, electrum, database, &state);
+    if (session == NULL) {
+        free(psbt);
+        return;
+    }
+
+    snprintf(details, sizeof(details),
+             "{\"subaccount\": 0, \"addressees\": [{\"address\": \"%s\", \"satoshi\": 50000}]}",
+             address);
+    CHECK(GA_create_transaction(session, details, &output) == GA_OK);
+    CHECK(get_json_string(output, "psbt", psbt, sizeof(details)) == 0);
+    CHECK(output != NULL && strstr(output, "\"sent\":100000") != NULL);
+    GA_destroy_string(output);
+
+    snprintf(details, sizeof(details), "{\"subaccount\": 0, \"psbt\": \"%s\"}", psbt);
+    if (GA_sign_transaction(session, details, &output) != GA_OK) {
+        print_error();
+        failures++;
+    }
+    CHECK(output != NULL && strstr(output, "\"finalized\":true") != NULL);
+    CHECK(get_json_string(output, "psbt", psbt, sizeof(details)) == 0);
+    GA_destroy_string(output);
+    output = NULL;
+    CHECK(state.methods == 1);
+    CHECK(state.codes == 1);
+
+    snprintf(details, sizeof(details), "{\"subaccount\": 0, \"psbt\": \"%s\"}", psbt);
+    CHECK(GA_send_transaction(session, details, &output) == GA_OK);
+    CHECK(get_json_string(output, "txid", txid, sizeof(txid)) == 0);
+    CHECK(strlen(txid) == 64);
+    printf("stub txid: %s\n", txid);
+    GA_destroy_string(output);
+
+    CHECK(GA_destroy_session(session) == GA_OK);
+    free(psbt);
+}
+
+static void test_invalid_config(void)
+{
+    GA_session *session = NULL;
+    char *details = NULL;
+
+    CHECK(GA_create_session("{not json", &session) == GA_ERROR);
+    CHECK(session == NULL);
+
+    CHECK(GA_get_thread_error_details(&details) == GA_OK);
+    CHECK(strstr(details, "details") != NULL);
+    GA_destroy_string(details);
+}
+
+static void test_not_logged_in(void)
+{
+    GA_session *session = NULL;
+    char *output = NULL;
+
+    CHECK(GA_create_session(NULL, &session) == GA_OK);
+    CHECK(session != NULL);
+
+    CHECK(GA_get_subaccounts(session, &output) == GA_ERROR);
+    CHECK(output == NULL);
+    CHECK(GA_get_receive_address(session, "{\"subaccount\": 0}", &output) == GA_ERROR);
+    CHECK(GA_login(session, "{}") == GA_ERROR);
+
+    CHECK(GA_set_twofactor_callback(session, twofactor_callback, NULL) == GA_OK);
+    CHECK(GA_destroy_session(session) == GA_OK);
+}
+
+static void test_null_session(void)
+{
+    char *output = NULL;
+
+    CHECK(GA_get_subaccounts(NULL, &output) == GA_ERROR);
+    CHECK(GA_destroy_session(NULL) == GA_OK);
+    GA_destroy_string(NULL);
+}
+
+static void test_login(const char *mnemonic)
+{
+    GA_session *session = NULL;
+    char details[512];
+    char *output = NULL;
+
+    CHECK(GA_create_session("{\"database_path\": \"neerg-test-db\"}", &session) == GA_OK);
+    CHECK(GA_set_twofactor_callback(session, twofactor_callback, NULL) == GA_OK);
+
+    snprintf(details, sizeof(details), "{\"mnemonic\": \"%s\"}", mnemonic);
+    if (GA_login(session, details) != GA_OK) {
+        print_error();
+        failures++;
+        GA_destroy_session(session);
+        return;
+    }
+
+    CHECK(GA_get_subaccounts(session, &output) == GA_OK);
+    CHECK(output != NULL && output[0] == '[');
+    printf("subaccounts: %s\n", output);
+    GA_destroy_string(output);
+
+    CHECK(GA_get_receive_address(session, "{\"subaccount\": 0}", &output) == GA_OK);
+    CHECK(output != NULL && strstr(output, "address") != NULL);
+    printf("address: %s\n", output);
+    GA_destroy_string(output);
+
+    CHECK(GA_create_transaction(session, "{\"subaccount\": 0, \"addressees\": []}", &output) ==
+          GA_ERROR);
+
+    CHECK(GA_destroy_session(session) == GA_OK);
+}
+
+int main(void)
+{
+    const char *mnemonic = getenv("NEERG_TEST_MNEMONIC");
+    const char *stub_endpoint = getenv("NEERG_TEST_STUB_ENDPOINT");
+    const char *stub_electrum = getenv("NEERG_TEST_STUB_ELECTRUM");
+
+    test_invalid_config();
+    test_not_logged_in();
+    test_null_session();
+
+    if (stub_endpoint != NULL && stub_electrum != NULL) {
+        test_stub(stub_endpoint, stub_electrum);
+    } else {
+        printf("NEERG_TEST_STUB_ENDPOINT or NEERG_TEST_STUB_ELECTRUM not set, skipping the stub "
+               "tests\n");
+    }
+
+    if (mnemonic != NULL) {
+        test_login(mnemonic);
+    } else {
+        printf("NEERG_TEST_MNEMONIC not set, skipping the login tests\n");
+    }
+
+    if (failures != 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return EXIT_FAILURE;
+    }
+    printf("all checks passed\n");
+    return EXIT_SUCCESS;
+}
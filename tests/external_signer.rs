@@ -0,0 +1,137 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bdk::bitcoin;
+
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{hash160, Hash};
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+use neerg::{ExternalSigner, SoftwareSigner, UserSigner};
+
+const MESSAGE: &str = "greenaddress.it      login abcdef";
+
+fn get_software_signer() -> SoftwareSigner {
+    let seed = [0x42u8; 32];
+    SoftwareSigner::new(ExtendedPrivKey::new_master(Network::Testnet, &seed).unwrap())
+}
+
+/// PSBT spending a p2wpkh output of the key at `path`
+fn get_unsigned_psbt(signer: &dyn UserSigner, path: &DerivationPath) -> PartiallySignedTransaction {
+    let public_key = signer.get_xpub(path).unwrap().public_key;
+    let script_pubkey = Builder::new()
+        .push_int(0)
+        .push_slice(&hash160::Hash::hash(&public_key.to_bytes())[..])
+        .into_script();
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Script::new(),
+            sequence: 0xFFFF_FFFD,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value: 9_000,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    };
+
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+    psbt.inputs[0].witness_utxo = Some(TxOut {
+        value: 10_000,
+        script_pubkey,
+    });
+    psbt.inputs[0].hd_keypaths.insert(
+        public_key,
+        (signer.get_fingerprint().unwrap(), path.clone()),
+    );
+
+    psbt
+}
+
+/// Write a stand-in signer that only answers the expected requests, with the responses computed
+/// by `signer`
+fn write_stub(signer: &SoftwareSigner, path: &DerivationPath) -> PathBuf {
+    let xpub = signer.get_xpub(path).unwrap();
+    let signature = signer.sign_message(path, MESSAGE).unwrap();
+
+    let unsigned = get_unsigned_psbt(signer, path);
+    let mut signed = unsigned.clone();
+    signer.sign_psbt(&mut signed).unwrap();
+
+    let script = format!(
+        r#"#!/bin/sh
+case "$1" in
+getxpub)
+    [ "$2" = "{path}" ] && echo '{{"xpub": "{xpub}"}}' && exit 0
+    ;;
+signmessage)
+    [ "$2" = "{message}" ] && [ "$3" = "{path}" ] && echo '{{"signature": "{signature}"}}' && exit 0
+    ;;
+signtx)
+    read -r psbt
+    [ "$#" = 1 ] && [ "$psbt" = "{unsigned}" ] && echo '{{"psbt": "{signed}"}}' && exit 0
+    ;;
+esac
+echo '{{"error": "unexpected request"}}'
+exit 1
+"#,
+        path = path,
+        xpub = xpub,
+        message = MESSAGE,
+        signature = base64::encode(&signature.serialize_compact()[..]),
+        unsigned = base64::encode(&serialize(&unsigned)),
+        signed = base64::encode(&serialize(&signed)),
+    );
+
+    let stub = std::env::temp_dir().join(format!("neerg-signer-stub-{}", std::process::id()));
+    fs::write(&stub, script).unwrap();
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+    stub
+}
+
+#[test]
+fn test_external_signer_matches_software_signer() {
+    let software = get_software_signer();
+    let path = DerivationPath::from_str("m/3'/1/7").unwrap();
+
+    let stub = write_stub(&software, &path);
+    let external = ExternalSigner::new(&stub, vec![]);
+
+    assert_eq!(
+        external.get_xpub(&path).unwrap(),
+        software.get_xpub(&path).unwrap()
+    );
+    assert_eq!(
+        external.sign_message(&path, MESSAGE).unwrap(),
+        software.sign_message(&path, MESSAGE).unwrap()
+    );
+
+    let mut external_psbt = get_unsigned_psbt(&software, &path);
+    let mut software_psbt = external_psbt.clone();
+    external.sign_psbt(&mut external_psbt).unwrap();
+    software.sign_psbt(&mut software_psbt).unwrap();
+
+    assert_eq!(software_psbt.inputs[0].partial_sigs.len(), 1);
+    assert_eq!(serialize(&external_psbt), serialize(&software_psbt));
+
+    // Requests the stub doesn't expect are reported as errors
+    let other_path = DerivationPath::from_str("m/0").unwrap();
+    assert_eq!(
+        external.get_xpub(&other_path).unwrap_err().to_string(),
+        "unexpected request"
+    );
+
+    fs::remove_file(stub).unwrap();
+}